@@ -0,0 +1,332 @@
+use std::collections::HashSet;
+
+use image::DynamicImage;
+
+use crate::metrics::Plane;
+use crate::opts::{
+    decode_image, grayscale_tolerance, is_gray_pixel, ChromaSubsampling, CompressionOptions, FormatChoice, OutputFormat,
+};
+
+/// Distinct colours are counted up to this many
+pub(crate) const MAX_COUNTED_COLORS: usize = 1 << 16;
+
+/// Luma step (|dx| + |dy|) that counts as a strong edge
+const EDGE_THRESHOLD: f64 = 64.0;
+/// Largest step to any neighbour for a pixel to count as lying in a smooth area
+const SMOOTH_THRESHOLD: f64 = 16.0;
+/// Mean absolute Laplacian above which smooth areas look like sensor or film noise
+const PHOTO_NOISE: f64 = 1.0;
+/// More colours than this in a screenshot means it shows pictures, not just anti-aliased UI
+const PICTURE_COLORS: usize = 4096;
+
+/// What kind of content an image holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentClass {
+    /// Camera images: noisy, few exactly repeated pixels
+    Photo,
+    /// Logos, icons, charts and illustrations: flat areas, no text
+    Graphic,
+    /// Flat UI areas with text and possibly embedded pictures
+    Screenshot,
+    /// Dark text on a light, grayscale page
+    Document,
+}
+
+impl ContentClass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentClass::Photo => "photo",
+            ContentClass::Graphic => "graphic",
+            ContentClass::Screenshot => "screenshot",
+            ContentClass::Document => "document",
+        }
+    }
+}
+
+/// Pixel statistics of an image and the content class they point to
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentAnalysis {
+    pub class: ContentClass,
+    /// Distinct RGBA colours, counted up to `MAX_COUNTED_COLORS`
+    pub unique_colors: usize,
+    /// Fraction of pixels equal to their left neighbour
+    pub flat_fraction: f64,
+    /// Fraction of pixels on a strong luma edge
+    pub edge_density: f64,
+    /// Mean luma gradient (half the central differences across and down), in levels per pixel
+    pub spatial_frequency: f64,
+    /// Fraction of busy 8x8 blocks that are two-toned like text strokes (0-1)
+    pub text_likeness: f64,
+    /// Mean absolute Laplacian of luma in smooth areas; 0 for clean synthetic content
+    pub noise: f64,
+    /// No pixel has visible colour (channels within a few levels of each other)
+    pub grayscale: bool,
+    /// Some pixel is not fully opaque
+    pub has_alpha: bool,
+}
+
+impl ContentAnalysis {
+    /// Output format suited to the content:
+    /// - photos: JPEG, or WebP when transparency has to be kept
+    /// - documents, graphics of up to 256 colours and plain screenshots: palette PNG
+    /// - graphics with more colours (smooth gradients): lossless PNG
+    /// - screenshots holding pictures: JPEG with full-resolution chroma, so coloured
+    ///   text stays sharp, or WebP when transparency has to be kept
+    pub fn recommended_format(&self) -> OutputFormat {
+        match self.class {
+            ContentClass::Photo if self.has_alpha => OutputFormat::WebP,
+            ContentClass::Photo => OutputFormat::Jpeg,
+            ContentClass::Document => OutputFormat::Png,
+            ContentClass::Graphic if self.unique_colors <= 256 => OutputFormat::Png,
+            ContentClass::Graphic => OutputFormat::PngLossless,
+            ContentClass::Screenshot if self.unique_colors <= PICTURE_COLORS => OutputFormat::Png,
+            ContentClass::Screenshot if self.has_alpha => OutputFormat::WebP,
+            ContentClass::Screenshot => OutputFormat::Jpeg,
+        }
+    }
+
+    /// Chroma subsampling for JPEG output: halving chroma blurs the colour edges of text and graphics
+    pub fn recommended_subsampling(&self) -> ChromaSubsampling {
+        match self.class {
+            ContentClass::Photo => ChromaSubsampling::Yuv420,
+            _ => ChromaSubsampling::Yuv444,
+        }
+    }
+
+    /// How busy the image is, 0 (flat) to 1 (dense texture), from its spatial
+    /// frequency, noise and colour count
+    pub fn complexity(&self) -> f64 {
+        let frequency = (self.spatial_frequency / 24.0).min(1.0);
+        let noise = (self.noise / 6.0).min(1.0);
+        let colors = ((self.unique_colors.max(1) as f64).log2() / 16.0).min(1.0);
+        0.5 * frequency + 0.3 * noise + 0.2 * colors
+    }
+
+    /// `base` quality moved by up to `range` points: down for simple images, where
+    /// artifacts don't show, up for busy textures, which need the bits
+    pub fn adapt_quality(&self, base: u8, range: u8) -> u8 {
+        let offset = ((self.complexity() - 0.5) * 2.0 * range as f64).round() as i32;
+        (base as i32 + offset).clamp(0, 100) as u8
+    }
+
+    /// `options` with the output format and JPEG chroma subsampling recommended for this content
+    pub fn apply(&self, options: &CompressionOptions) -> CompressionOptions {
+        CompressionOptions {
+            format: FormatChoice::Fixed(self.recommended_format()),
+            chroma_subsampling: self.recommended_subsampling(),
+            ..options.clone()
+        }
+    }
+
+    /// Analysis as `key=value` lines (`java.util.Properties` format)
+    pub fn summary(&self) -> String {
+        [
+            format!("class={}", self.class.name()),
+            format!("recommended_format={}", self.recommended_format().name()),
+            format!("recommended_subsampling={}", self.recommended_subsampling().name()),
+            format!("unique_colors={}", self.unique_colors),
+            format!("flat_fraction={:.4}", self.flat_fraction),
+            format!("edge_density={:.4}", self.edge_density),
+            format!("spatial_frequency={:.4}", self.spatial_frequency),
+            format!("text_likeness={:.4}", self.text_likeness),
+            format!("noise={:.4}", self.noise),
+            format!("grayscale={}", self.grayscale),
+            format!("has_alpha={}", self.has_alpha),
+            format!("complexity={:.4}", self.complexity()),
+        ]
+        .join("\n")
+    }
+}
+
+/// Decode an image (upright, CMYK-aware) and classify its content
+pub fn analyze_image(data: &[u8]) -> Result<ContentAnalysis, String> {
+    let img = decode_image(data, &CompressionOptions::default())?;
+    Ok(analyze_pixels(&img, grayscale_tolerance(data)))
+}
+
+/// Classify decoded pixels from their colour count, flatness, edges, text-likeness and noise
+pub fn analyze_decoded(img: &DynamicImage) -> ContentAnalysis {
+    analyze_pixels(img, 0)
+}
+
+/// [`analyze_decoded`] for pixels whose channels may differ by up to `gray_tolerance`
+/// and still count as gray, see [`grayscale_tolerance`]
+pub(crate) fn analyze_pixels(img: &DynamicImage, gray_tolerance: u8) -> ContentAnalysis {
+    let rgba = img.to_rgba8();
+    let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+    let pixels: Vec<u32> = rgba.pixels().map(|p| u32::from_le_bytes(p.0)).collect();
+
+    let mut colors = HashSet::new();
+    for &pixel in &pixels {
+        if colors.len() >= MAX_COUNTED_COLORS {
+            break;
+        }
+        colors.insert(pixel);
+    }
+    let flat = pixels
+        .chunks_exact(width.max(1))
+        .map(|row| row.windows(2).filter(|pair| pair[0] == pair[1]).count())
+        .sum::<usize>();
+    let flat_fraction = flat as f64 / (height * width.saturating_sub(1)).max(1) as f64;
+    let has_alpha = rgba.pixels().any(|p| p[3] < 255);
+    let grayscale = rgba.pixels().all(|p| is_gray_pixel(&p.0, gray_tolerance) || p[3] == 0);
+
+    let luma = Plane::luma(img, [255, 255, 255]);
+    let (edge_density, spatial_frequency, noise) = gradient_statistics(&luma);
+    let text_likeness = text_likeness(&luma);
+
+    let class = if flat_fraction < 0.5 && noise >= PHOTO_NOISE {
+        ContentClass::Photo
+    } else if text_likeness >= 0.5 && edge_density >= 0.02 && grayscale {
+        ContentClass::Document
+    } else if text_likeness >= 0.3 && edge_density >= 0.02 {
+        ContentClass::Screenshot
+    } else if flat_fraction < 0.2 && noise >= PHOTO_NOISE / 2.0 {
+        // Smooth, soft photos (skies, studio shots) have little noise but almost no flat runs
+        ContentClass::Photo
+    } else {
+        ContentClass::Graphic
+    };
+
+    ContentAnalysis {
+        class,
+        unique_colors: colors.len(),
+        flat_fraction,
+        edge_density,
+        spatial_frequency,
+        text_likeness,
+        noise,
+        grayscale,
+        has_alpha,
+    }
+}
+
+/// Fraction of pixels on a strong edge, the mean gradient, and the mean absolute
+/// Laplacian in smooth areas
+fn gradient_statistics(luma: &Plane) -> (f64, f64, f64) {
+    let (width, height) = (luma.width, luma.height);
+    if width < 3 || height < 3 {
+        return (0.0, 0.0, 0.0);
+    }
+    let at = |x: usize, y: usize| luma.data[y * width + x];
+    let (mut edges, mut gradient_sum, mut laplacian_sum, mut smooth) = (0usize, 0.0, 0.0, 0usize);
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let c = at(x, y);
+            let neighbours = [at(x - 1, y), at(x + 1, y), at(x, y - 1), at(x, y + 1)];
+            let gradient = (neighbours[1] - neighbours[0]).abs() / 2.0 + (neighbours[3] - neighbours[2]).abs() / 2.0;
+            gradient_sum += gradient;
+            if gradient >= EDGE_THRESHOLD {
+                edges += 1;
+            } else if neighbours.iter().all(|n| (n - c).abs() < SMOOTH_THRESHOLD) {
+                let laplacian = 4.0 * c - neighbours.iter().sum::<f64>();
+                laplacian_sum += laplacian.abs() / 4.0;
+                smooth += 1;
+            }
+        }
+    }
+    let interior = ((width - 2) * (height - 2)) as f64;
+    let noise = if smooth == 0 { 0.0 } else { laplacian_sum / smooth as f64 };
+    (edges as f64 / interior, gradient_sum / interior, noise)
+}
+
+/// Fraction of high-contrast 8x8 blocks that look like glyphs
+fn text_likeness(luma: &Plane) -> f64 {
+    let busy: Vec<bool> = glyph_blocks(luma).into_iter().flatten().collect();
+    let glyphs = busy.iter().filter(|&&glyph| glyph).count();
+    if busy.is_empty() { 0.0 } else { glyphs as f64 / busy.len() as f64 }
+}
+
+/// For each 8x8 block in row-major order (`width / 8` per row): `None` when it
+/// has little contrast, else whether it looks like a glyph. Glyph pixels sit at
+/// one of two levels (photo edges ramp through the levels in between) and several
+/// rows or columns cross a stroke thinner than the block (shape outlines cross once).
+pub(crate) fn glyph_blocks(luma: &Plane) -> Vec<Option<bool>> {
+    let mut blocks = Vec::with_capacity((luma.width / 8) * (luma.height / 8));
+    for by in 0..luma.height / 8 {
+        for bx in 0..luma.width / 8 {
+            let at = |x: usize, y: usize| luma.data[(by * 8 + y) * luma.width + bx * 8 + x];
+            let block: Vec<f64> = (0..64).map(|i| at(i % 8, i / 8)).collect();
+            let min = block.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = block.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let range = max - min;
+            if range < 96.0 {
+                blocks.push(None);
+                continue;
+            }
+            let near_extreme = block.iter().filter(|&&v| v - min <= range / 6.0 || max - v <= range / 6.0).count();
+            let dark = |x: usize, y: usize| at(x, y) < min + range / 2.0;
+            let crosses_stroke = |line: &dyn Fn(usize) -> bool| (1..8).filter(|&i| line(i) != line(i - 1)).count() >= 2;
+            let stroke_lines = (0..8)
+                .filter(|&r| crosses_stroke(&|x| dark(x, r)))
+                .chain((0..8).filter(|&c| crosses_stroke(&|y| dark(c, y))))
+                .count();
+            blocks.push(Some(near_extreme * 100 >= 64 * 85 && stroke_lines >= 3));
+        }
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    /// Deterministic pseudo-random value in 0..n
+    fn hash(x: u32, y: u32, n: u32) -> u32 {
+        (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)).wrapping_mul(2_654_435_761) % n
+    }
+
+    fn photo() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(128, 96, |x, y| {
+            let n = hash(x, y, 24) as i32 - 12;
+            let c = |base: i32| (base + n).clamp(0, 255) as u8;
+            Rgb([c(60 + x as i32), c(90 + y as i32), c(140)])
+        }))
+    }
+
+    /// Glyph-like strokes: short dark bars in lines of "text" on a plain background
+    fn text_on(background: [u8; 3], ink: [u8; 3], width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let in_line = y % 16 >= 4 && y % 16 < 12;
+            let stroke = (x % 6 < 2 && hash(x / 6, y / 16, 5) != 0) || (y % 16 == 8 && x % 12 < 7);
+            Rgb(if in_line && stroke { ink } else { background })
+        })
+    }
+
+    #[test]
+    fn test_classify_content() {
+        let analysis = analyze_decoded(&photo());
+        assert_eq!(analysis.class, ContentClass::Photo, "{}", analysis.summary());
+        assert_eq!(analysis.recommended_format(), OutputFormat::Jpeg);
+        assert_eq!(analysis.recommended_subsampling(), ChromaSubsampling::Yuv420);
+
+        let logo = DynamicImage::ImageRgba8(RgbaImage::from_fn(96, 96, |x, y| {
+            let d = (x as i32 - 48).pow(2) + (y as i32 - 48).pow(2);
+            if d < 900 { Rgba([220, 30, 30, 255]) } else if d < 1600 { Rgba([255, 255, 255, 255]) } else { Rgba([0, 0, 0, 0]) }
+        }));
+        let analysis = analyze_decoded(&logo);
+        assert_eq!(analysis.class, ContentClass::Graphic, "{}", analysis.summary());
+        assert_eq!((analysis.unique_colors, analysis.has_alpha), (3, true));
+        assert_eq!(analysis.recommended_format(), OutputFormat::Png);
+
+        let page = DynamicImage::ImageRgb8(text_on([250, 250, 250], [20, 20, 20], 160, 128));
+        let analysis = analyze_decoded(&page);
+        assert_eq!(analysis.class, ContentClass::Document, "{}", analysis.summary());
+        assert_eq!(analysis.recommended_format(), OutputFormat::Png);
+
+        // Coloured text next to a photo
+        let mut screen = text_on([236, 240, 245], [30, 60, 200], 256, 192);
+        let picture = photo().to_rgb8();
+        for (x, y, pixel) in screen.enumerate_pixels_mut() {
+            if x >= 128 && y >= 96 {
+                *pixel = *picture.get_pixel(x - 128, y - 96);
+            }
+        }
+        let analysis = analyze_decoded(&DynamicImage::ImageRgb8(screen));
+        assert_eq!(analysis.class, ContentClass::Screenshot, "{}", analysis.summary());
+        assert_eq!(analysis.recommended_format(), OutputFormat::Jpeg);
+        assert_eq!(analysis.recommended_subsampling(), ChromaSubsampling::Yuv444);
+        assert!(analysis.summary().contains("class=screenshot"));
+    }
+}
@@ -0,0 +1,267 @@
+use image::{ColorType, DynamicImage, ImageBuffer, RgbImage};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+use crate::jpeg::{jpeg_adobe_transform, jpeg_color_model, jpeg_icc_profile, JpegColorModel};
+use crate::jpeg_transcode::catch_libjpeg;
+
+/// True for JPEGs whose samples are stored as CMYK or YCCK
+pub(crate) fn is_cmyk_jpeg(data: &[u8]) -> bool {
+    matches!(
+        jpeg_color_model(data),
+        Some(JpegColorModel::Cmyk | JpegColorModel::Ycck)
+    )
+}
+
+/// Decode a CMYK or YCCK JPEG to RGB.
+///
+/// libjpeg turns YCCK into CMYK; files carrying an Adobe APP14 marker store
+/// inverted samples (Photoshop convention) and are flipped back to ink amounts.
+/// An embedded CMYK ICC profile drives the conversion to sRGB; without one a
+/// simple subtractive ink model is used.
+pub(crate) fn decode_cmyk_jpeg(data: &[u8]) -> Result<RgbImage, String> {
+    let (width, height, mut cmyk) = catch_libjpeg(|| -> std::io::Result<_> {
+        let mut decompress = mozjpeg::Decompress::new_mem(data)?
+            .to_colorspace(mozjpeg::ColorSpace::JCS_CMYK)?;
+        let (width, height) = (decompress.width(), decompress.height());
+        let pixels = decompress.read_scanlines::<u8>()?;
+        decompress.finish()?;
+        Ok((width, height, pixels))
+    })?
+    .map_err(|e| format!("Failed to decode CMYK JPEG: {}", e))?;
+
+    if jpeg_adobe_transform(data).is_some() {
+        for v in cmyk.iter_mut() {
+            *v = 255 - *v;
+        }
+    }
+
+    let rgb = jpeg_icc_profile(data)
+        .and_then(|icc| cmyk_to_srgb_with_profile(&icc, &cmyk).ok())
+        .unwrap_or_else(|| naive_cmyk_to_rgb(&cmyk));
+
+    RgbImage::from_raw(width as u32, height as u32, rgb)
+        .ok_or_else(|| "Failed to decode CMYK JPEG: truncated scanlines".to_string())
+}
+
+/// Convert ink amounts (0 = no ink) to sRGB through a CMYK ICC profile
+fn cmyk_to_srgb_with_profile(icc: &[u8], cmyk: &[u8]) -> Result<Vec<u8>, String> {
+    let profile = ColorProfile::new_from_slice(icc).map_err(|e| format!("Invalid ICC profile: {:?}", e))?;
+    if profile.color_space != DataColorSpace::Cmyk {
+        return Err("ICC profile is not a CMYK profile".into());
+    }
+    // moxcms stores 8-bit CMYK with the RGBA layout
+    let transform = profile
+        .create_transform_8bit(Layout::Rgba, &ColorProfile::new_srgb(), Layout::Rgb, TransformOptions::default())
+        .map_err(|e| format!("Failed to create CMYK transform: {:?}", e))?;
+    let mut rgb = vec![0u8; cmyk.len() / 4 * 3];
+    transform
+        .transform(cmyk, &mut rgb)
+        .map_err(|e| format!("Failed to convert CMYK: {:?}", e))?;
+    Ok(rgb)
+}
+
+/// Convert decoded pixels from their embedded RGB or gray ICC profile to sRGB.
+///
+/// Alpha is carried through; 16-bit images are converted at 16 bits.
+pub(crate) fn convert_to_srgb(img: &DynamicImage, icc: &[u8]) -> Result<DynamicImage, String> {
+    let profile = ColorProfile::new_from_slice(icc).map_err(|e| format!("Invalid ICC profile: {:?}", e))?;
+    let alpha = img.color().has_alpha();
+    let (src_layout, channels) = match (profile.color_space, alpha) {
+        (DataColorSpace::Rgb, false) => (Layout::Rgb, 3),
+        (DataColorSpace::Rgb, true) => (Layout::Rgba, 4),
+        (DataColorSpace::Gray, false) => (Layout::Gray, 1),
+        (DataColorSpace::Gray, true) => (Layout::GrayAlpha, 2),
+        (space, _) => return Err(format!("Cannot convert {:?} profile to sRGB", space)),
+    };
+    let dst_layout = if alpha { Layout::Rgba } else { Layout::Rgb };
+    let (width, height) = (img.width(), img.height());
+    let pixel_count = width as usize * height as usize;
+    let srgb = ColorProfile::new_srgb();
+    let transform_error = |e| format!("Failed to convert to sRGB: {:?}", e);
+
+    let sixteen_bit = matches!(img.color(), ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16);
+    if sixteen_bit {
+        let src: Vec<u16> = match channels {
+            1 => img.to_luma16().into_raw(),
+            2 => img.to_luma_alpha16().into_raw(),
+            3 => img.to_rgb16().into_raw(),
+            _ => img.to_rgba16().into_raw(),
+        };
+        let mut dst = vec![0u16; pixel_count * if alpha { 4 } else { 3 }];
+        profile
+            .create_transform_16bit(src_layout, &srgb, dst_layout, TransformOptions::default())
+            .map_err(transform_error)?
+            .transform(&src, &mut dst)
+            .map_err(transform_error)?;
+        return Ok(if alpha {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, dst).ok_or("Pixel buffer size mismatch")?)
+        } else {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, dst).ok_or("Pixel buffer size mismatch")?)
+        });
+    }
+
+    let src: Vec<u8> = match channels {
+        1 => img.to_luma8().into_raw(),
+        2 => img.to_luma_alpha8().into_raw(),
+        3 => img.to_rgb8().into_raw(),
+        _ => img.to_rgba8().into_raw(),
+    };
+    let mut dst = vec![0u8; pixel_count * if alpha { 4 } else { 3 }];
+    profile
+        .create_transform_8bit(src_layout, &srgb, dst_layout, TransformOptions::default())
+        .map_err(transform_error)?
+        .transform(&src, &mut dst)
+        .map_err(transform_error)?;
+    Ok(if alpha {
+        DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, dst).ok_or("Pixel buffer size mismatch")?)
+    } else {
+        DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, dst).ok_or("Pixel buffer size mismatch")?)
+    })
+}
+
+/// True for matrix/TRC RGB profiles that are colorimetrically sRGB, whatever
+/// their name or vendor (HP/Microsoft, Apple, Google, ... all ship one)
+pub(crate) fn is_srgb_profile(icc: &[u8]) -> bool {
+    let Ok(profile) = ColorProfile::new_from_slice(icc) else {
+        return false;
+    };
+    let srgb = ColorProfile::new_srgb();
+    if profile.color_space != DataColorSpace::Rgb || profile.lut_a_to_b_perceptual.is_some() {
+        return false;
+    }
+
+    let close = |a: &moxcms::Xyzd, b: &moxcms::Xyzd| {
+        (a.x - b.x).abs() < 0.002 && (a.y - b.y).abs() < 0.002 && (a.z - b.z).abs() < 0.002
+    };
+    let colorants_match = close(&profile.red_colorant, &srgb.red_colorant)
+        && close(&profile.green_colorant, &srgb.green_colorant)
+        && close(&profile.blue_colorant, &srgb.blue_colorant);
+
+    let curves_match = [&profile.red_trc, &profile.green_trc, &profile.blue_trc].iter().all(|trc| {
+        match (profile.build_8bit_lin_table(trc), srgb.build_8bit_lin_table(&srgb.red_trc)) {
+            (Ok(table), Ok(reference)) => table.iter().zip(reference.iter()).all(|(a, b)| (a - b).abs() < 0.002),
+            _ => false,
+        }
+    });
+    colorants_match && curves_match
+}
+
+/// Rendering intent from the ICC header (0 = perceptual ... 3 = absolute colorimetric)
+pub(crate) fn icc_rendering_intent(icc: &[u8]) -> u8 {
+    icc.get(64..68)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]).min(3) as u8)
+        .unwrap_or(0)
+}
+
+/// Profile-less fallback: R = (1 - C)(1 - K) and so on
+pub(crate) fn naive_cmyk_to_rgb(cmyk: &[u8]) -> Vec<u8> {
+    cmyk.chunks_exact(4)
+        .flat_map(|p| {
+            let white = 255 - p[3] as u32;
+            [
+                ((255 - p[0] as u32) * white / 255) as u8,
+                ((255 - p[1] as u32) * white / 255) as u8,
+                ((255 - p[2] as u32) * white / 255) as u8,
+            ]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a solid CMYK JPEG the way Photoshop does: Adobe marker plus inverted samples
+    fn cmyk_jpeg(ink: [u8; 4], jpeg_color_space: mozjpeg::ColorSpace) -> Vec<u8> {
+        let (width, height) = (24, 16);
+        let inverted: Vec<u8> = ink.iter().map(|v| 255 - v).collect();
+        let pixels: Vec<u8> = inverted.iter().copied().cycle().take(width * height * 4).collect();
+
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_CMYK);
+        comp.set_color_space(jpeg_color_space);
+        comp.set_size(width, height);
+        comp.set_quality(95.0);
+        let mut jpeg = Vec::new();
+        let mut started = comp.start_compress(&mut jpeg).unwrap();
+        started.write_scanlines(&pixels).unwrap();
+        started.finish().unwrap();
+        jpeg
+    }
+
+    fn assert_close(actual: &[u8], expected: [u8; 3]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(a.abs_diff(e) <= 6, "got {:?}, expected {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_detects_cmyk_and_ycck() {
+        let cmyk = cmyk_jpeg([0, 0, 0, 0], mozjpeg::ColorSpace::JCS_CMYK);
+        assert_eq!(jpeg_color_model(&cmyk), Some(JpegColorModel::Cmyk));
+        assert_eq!(jpeg_adobe_transform(&cmyk), Some(0));
+        assert!(is_cmyk_jpeg(&cmyk));
+
+        let ycck = cmyk_jpeg([0, 0, 0, 0], mozjpeg::ColorSpace::JCS_YCCK);
+        assert_eq!(jpeg_color_model(&ycck), Some(JpegColorModel::Ycck));
+        assert_eq!(jpeg_adobe_transform(&ycck), Some(2));
+        assert!(is_cmyk_jpeg(&ycck));
+    }
+
+    #[test]
+    fn test_decode_adobe_inverted_cmyk() {
+        let cyan = decode_cmyk_jpeg(&cmyk_jpeg([255, 0, 0, 0], mozjpeg::ColorSpace::JCS_CMYK)).unwrap();
+        assert_eq!(cyan.dimensions(), (24, 16));
+        assert_close(&cyan.get_pixel(5, 5).0, [0, 255, 255]);
+
+        let red = decode_cmyk_jpeg(&cmyk_jpeg([0, 255, 255, 0], mozjpeg::ColorSpace::JCS_YCCK)).unwrap();
+        assert_close(&red.get_pixel(5, 5).0, [255, 0, 0]);
+
+        let gray = decode_cmyk_jpeg(&cmyk_jpeg([0, 0, 0, 128], mozjpeg::ColorSpace::JCS_CMYK)).unwrap();
+        assert_close(&gray.get_pixel(5, 5).0, [127, 127, 127]);
+    }
+
+    #[test]
+    fn test_compress_cmyk_jpeg_to_rgb() {
+        let input = cmyk_jpeg([0, 255, 255, 0], mozjpeg::ColorSpace::JCS_YCCK);
+        let compressed = crate::opts::do_jpeg_compression(&input, 80).unwrap();
+        assert_ne!(jpeg_color_model(&compressed), Some(JpegColorModel::Ycck));
+        let decoded = image::load_from_memory(&compressed).unwrap().to_rgb8();
+        assert_close(&decoded.get_pixel(3, 3).0, [255, 0, 0]);
+    }
+
+    #[test]
+    fn test_is_srgb_profile() {
+        assert!(is_srgb_profile(&ColorProfile::new_srgb().encode().unwrap()));
+        assert!(!is_srgb_profile(&ColorProfile::new_display_p3().encode().unwrap()));
+        assert!(!is_srgb_profile(&ColorProfile::new_adobe_rgb().encode().unwrap()));
+        assert!(!is_srgb_profile(b"not a profile"));
+    }
+
+    #[test]
+    fn test_convert_to_srgb() {
+        // Display P3 pure green lies outside sRGB and clips to it; white stays white
+        let p3 = ColorProfile::new_display_p3().encode().unwrap();
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 { image::Rgba([0, 255, 0, 128]) } else { image::Rgba([255, 255, 255, 255]) }
+        }));
+        let converted = convert_to_srgb(&img, &p3).unwrap().to_rgba8();
+        let green = converted.get_pixel(0, 0).0;
+        assert!(green[0] < 10 && green[1] > 245 && green[2] < 10, "{:?}", green);
+        assert_eq!(green[3], 128);
+        assert_close(&converted.get_pixel(1, 0).0[..3], [255, 255, 255]);
+
+        // A mid-tone red in Adobe RGB is more saturated than the same numbers in sRGB
+        let adobe = ColorProfile::new_adobe_rgb().encode().unwrap();
+        let img = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(1, 1, image::Rgb([40000u16, 10000, 10000])));
+        let converted = convert_to_srgb(&img, &adobe).unwrap();
+        assert_eq!(converted.color(), ColorType::Rgb16);
+        let red = converted.to_rgb16().get_pixel(0, 0).0;
+        assert!(red[0] > 40000 && red[1] < 10000, "{:?}", red);
+    }
+
+    #[test]
+    fn test_naive_cmyk_to_rgb() {
+        assert_eq!(naive_cmyk_to_rgb(&[0, 0, 0, 0, 0, 0, 0, 255]), vec![255, 255, 255, 0, 0, 0]);
+        assert_eq!(naive_cmyk_to_rgb(&[0, 255, 0, 0]), vec![255, 0, 255]);
+    }
+}
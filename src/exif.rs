@@ -0,0 +1,469 @@
+use crate::jpeg::jpeg_segments;
+use crate::metadata::png_chunks;
+
+/// EXIF Orientation tag in IFD0
+pub(crate) const TAG_ORIENTATION: u16 = 0x0112;
+
+/// A TIFF structure (the body of an EXIF block) with its byte order
+pub(crate) struct Tiff<'a> {
+    pub data: &'a [u8],
+    pub little_endian: bool,
+}
+
+/// One 12-byte IFD entry
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IfdEntry {
+    pub tag: u16,
+    pub field_type: u16,
+    pub count: u32,
+    /// Offset of the entry itself inside the TIFF data
+    pub offset: usize,
+}
+
+impl<'a> Tiff<'a> {
+    /// Check the `II*\0` / `MM\0*` header
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let little_endian = match data.get(..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        Some(Self { data, little_endian })
+    }
+
+    pub fn u16_at(&self, pos: usize) -> Option<u16> {
+        let bytes = [*self.data.get(pos)?, *self.data.get(pos + 1)?];
+        Some(if self.little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) })
+    }
+
+    pub fn u32_at(&self, pos: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if self.little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    }
+
+    pub fn ifd0_offset(&self) -> Option<usize> {
+        self.u32_at(4).map(|o| o as usize)
+    }
+
+    /// Entries of the IFD starting at `ifd_offset` (truncated at the end of the data)
+    pub fn entries(&self, ifd_offset: usize) -> Vec<IfdEntry> {
+        let count = self.u16_at(ifd_offset).unwrap_or(0) as usize;
+        (0..count)
+            .map(|i| ifd_offset + 2 + i * 12)
+            .map_while(|offset| {
+                Some(IfdEntry {
+                    tag: self.u16_at(offset)?,
+                    field_type: self.u16_at(offset + 2)?,
+                    count: self.u32_at(offset + 4)?,
+                    offset,
+                })
+            })
+            .collect()
+    }
+
+    /// Offset of the IFD that follows the one at `ifd_offset` (IFD1 holds the thumbnail)
+    pub fn next_ifd(&self, ifd_offset: usize) -> Option<usize> {
+        let count = self.u16_at(ifd_offset)? as usize;
+        self.u32_at(ifd_offset + 2 + count * 12).filter(|&o| o != 0).map(|o| o as usize)
+    }
+
+    /// First SHORT value of an entry; values that fit in 4 bytes are stored inline
+    pub fn short_value(&self, entry: &IfdEntry) -> Option<u16> {
+        (entry.field_type == 3 && entry.count >= 1).then(|| self.u16_at(entry.offset + 8))?
+    }
+}
+
+/// Orientation (1-8) from IFD0 of an EXIF TIFF block
+pub(crate) fn exif_orientation(tiff: &[u8]) -> Option<u8> {
+    let tiff = Tiff::parse(tiff)?;
+    let entry = tiff.entries(tiff.ifd0_offset()?).into_iter().find(|e| e.tag == TAG_ORIENTATION)?;
+    tiff.short_value(&entry)
+        .filter(|v| (1..=8).contains(v))
+        .map(|v| v as u8)
+}
+
+/// Overwrite the Orientation value in place; false when the block has no Orientation tag
+pub(crate) fn set_exif_orientation(tiff: &mut [u8], orientation: u8) -> bool {
+    let Some(position) = Tiff::parse(tiff).and_then(|t| {
+        let entry = t.entries(t.ifd0_offset()?).into_iter().find(|e| e.tag == TAG_ORIENTATION)?;
+        t.short_value(&entry)?;
+        Some((entry.offset + 8, t.little_endian))
+    }) else {
+        return false;
+    };
+    let (pos, little_endian) = position;
+    let value = orientation as u16;
+    let bytes = if little_endian { value.to_le_bytes() } else { value.to_be_bytes() };
+    tiff[pos..pos + 2].copy_from_slice(&bytes);
+    true
+}
+
+/// IFD pointer tags whose value is a nested IFD
+pub(crate) const TAG_EXIF_IFD: u16 = 0x8769;
+pub(crate) const TAG_GPS_IFD: u16 = 0x8825;
+pub(crate) const TAG_INTEROP_IFD: u16 = 0xA005;
+
+/// A parsed IFD entry; values keep the byte order of the source TIFF
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ExifField {
+    pub tag: u16,
+    pub field_type: u16,
+    pub count: u32,
+    pub value: ExifValue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ExifValue {
+    Bytes(Vec<u8>),
+    Ifd(Vec<ExifField>),
+}
+
+/// Size in bytes of one value of a TIFF field type
+fn type_size(field_type: u16) -> Option<usize> {
+    match field_type {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 | 13 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    }
+}
+
+impl Tiff<'_> {
+    /// Parse an IFD and the Exif/GPS/Interop IFDs it points to; entries with
+    /// unknown types or out-of-range values are skipped
+    pub fn read_ifd(&self, ifd_offset: usize, depth: usize) -> Vec<ExifField> {
+        let mut fields = Vec::new();
+        for entry in self.entries(ifd_offset) {
+            let Some(size) = type_size(entry.field_type).and_then(|s| s.checked_mul(entry.count as usize)) else {
+                continue;
+            };
+            let start = if size <= 4 {
+                entry.offset + 8
+            } else {
+                match self.u32_at(entry.offset + 8) {
+                    Some(offset) => offset as usize,
+                    None => continue,
+                }
+            };
+            let is_pointer = matches!(entry.tag, TAG_EXIF_IFD | TAG_GPS_IFD | TAG_INTEROP_IFD);
+            let value = if is_pointer && depth < 4 {
+                match self.u32_at(entry.offset + 8) {
+                    Some(offset) if (offset as usize) < self.data.len() => {
+                        ExifValue::Ifd(self.read_ifd(offset as usize, depth + 1))
+                    }
+                    _ => continue,
+                }
+            } else {
+                match self.data.get(start..start.saturating_add(size)) {
+                    Some(bytes) => ExifValue::Bytes(bytes.to_vec()),
+                    None => continue,
+                }
+            };
+            fields.push(ExifField { tag: entry.tag, field_type: entry.field_type, count: entry.count, value });
+        }
+        fields
+    }
+}
+
+/// Parse IFD0 (with its sub-IFDs) of an EXIF TIFF block; returns the byte order too
+pub(crate) fn read_exif(tiff: &[u8]) -> Option<(Vec<ExifField>, bool)> {
+    let tiff = Tiff::parse(tiff)?;
+    Some((tiff.read_ifd(tiff.ifd0_offset()?, 0), tiff.little_endian))
+}
+
+/// Serialize IFD0 into a fresh TIFF block. IFD1 (the thumbnail) is not written.
+pub(crate) fn write_exif(ifd0: &[ExifField], little_endian: bool) -> Vec<u8> {
+    let mut out = if little_endian { b"II*\0".to_vec() } else { b"MM\0*".to_vec() };
+    push_u32(&mut out, 8, little_endian);
+    write_ifd(&mut out, ifd0, little_endian);
+    out
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32, little_endian: bool) {
+    out.extend_from_slice(&if little_endian { value.to_le_bytes() } else { value.to_be_bytes() });
+}
+
+fn patch_u32(out: &mut [u8], pos: usize, value: u32, little_endian: bool) {
+    out[pos..pos + 4].copy_from_slice(&if little_endian { value.to_le_bytes() } else { value.to_be_bytes() });
+}
+
+/// Append an IFD at the end of `out`, followed by its out-of-line values and sub-IFDs
+fn write_ifd(out: &mut Vec<u8>, fields: &[ExifField], little_endian: bool) {
+    let table = out.len();
+    let count = fields.len() as u16;
+    out.extend_from_slice(&if little_endian { count.to_le_bytes() } else { count.to_be_bytes() });
+    out.resize(table + 2 + fields.len() * 12 + 4, 0);
+
+    for (i, field) in fields.iter().enumerate() {
+        let entry = table + 2 + i * 12;
+        let tag = if little_endian { field.tag.to_le_bytes() } else { field.tag.to_be_bytes() };
+        let field_type = if little_endian { field.field_type.to_le_bytes() } else { field.field_type.to_be_bytes() };
+        out[entry..entry + 2].copy_from_slice(&tag);
+        out[entry + 2..entry + 4].copy_from_slice(&field_type);
+        match &field.value {
+            ExifValue::Bytes(bytes) => {
+                patch_u32(out, entry + 4, field.count, little_endian);
+                if bytes.len() <= 4 {
+                    out[entry + 8..entry + 8 + bytes.len()].copy_from_slice(bytes);
+                } else {
+                    // Values start on a word boundary
+                    if out.len() % 2 == 1 {
+                        out.push(0);
+                    }
+                    let offset = out.len() as u32;
+                    out.extend_from_slice(bytes);
+                    patch_u32(out, entry + 8, offset, little_endian);
+                }
+            }
+            ExifValue::Ifd(sub) => {
+                patch_u32(out, entry + 4, 1, little_endian);
+                if out.len() % 2 == 1 {
+                    out.push(0);
+                }
+                let offset = out.len() as u32;
+                write_ifd(out, sub, little_endian);
+                patch_u32(out, entry + 8, offset, little_endian);
+            }
+        }
+    }
+}
+
+/// IFD0 tags kept by the metadata whitelist: DateTime, Artist, Copyright
+const WHITELIST_IFD0: [u16; 3] = [0x0132, 0x013B, 0x8298];
+/// Exif IFD tags kept by the whitelist: DateTimeOriginal/Digitized and their offsets
+const WHITELIST_EXIF_IFD: [u16; 5] = [0x9003, 0x9004, 0x9010, 0x9011, 0x9012];
+
+/// Reduce an EXIF block to copyright, artist and capture date; `None` when none are present
+pub(crate) fn whitelist_exif(tiff: &[u8]) -> Option<Vec<u8>> {
+    let (ifd0, little_endian) = read_exif(tiff)?;
+    let mut kept: Vec<ExifField> = Vec::new();
+    for field in ifd0 {
+        match field.value {
+            ExifValue::Ifd(sub) if field.tag == TAG_EXIF_IFD => {
+                let sub: Vec<ExifField> =
+                    sub.into_iter().filter(|f| WHITELIST_EXIF_IFD.contains(&f.tag)).collect();
+                if !sub.is_empty() {
+                    kept.push(ExifField { value: ExifValue::Ifd(sub), ..field });
+                }
+            }
+            ExifValue::Bytes(_) if WHITELIST_IFD0.contains(&field.tag) => kept.push(field),
+            _ => {}
+        }
+    }
+    (!kept.is_empty()).then(|| write_exif(&kept, little_endian))
+}
+
+/// Tags privacy mode removes from IFD0 and the Exif IFD, with their names for the report
+const PRIVATE_TAGS: [(u16, &str); 9] = [
+    (TAG_GPS_IFD, "GPS"),
+    (0x013B, "Artist"),
+    (0x927C, "MakerNote"),
+    (0xA430, "CameraOwnerName"),
+    (0xA431, "BodySerialNumber"),
+    (0xA435, "LensSerialNumber"),
+    (0xC62F, "CameraSerialNumber"),
+    (0xA420, "ImageUniqueID"),
+    (0x0201, "JPEGInterchangeFormat"),
+];
+
+/// Remove location, the artist, device identifiers, maker notes and the thumbnail
+/// from an EXIF block. Returns the new block (the input untouched when nothing
+/// matched) and the names of what was removed.
+pub(crate) fn scrub_exif(tiff: &[u8]) -> (Vec<u8>, Vec<String>) {
+    let Some(parsed) = Tiff::parse(tiff) else {
+        return (tiff.to_vec(), Vec::new());
+    };
+    let Some(ifd0_offset) = parsed.ifd0_offset() else {
+        return (tiff.to_vec(), Vec::new());
+    };
+
+    let mut removed = Vec::new();
+    let private = |tag: u16| PRIVATE_TAGS.iter().find(|(t, _)| *t == tag).map(|(_, name)| *name);
+    let scrub = |fields: Vec<ExifField>, removed: &mut Vec<String>| -> Vec<ExifField> {
+        fields
+            .into_iter()
+            .filter(|f| match private(f.tag) {
+                Some(name) => {
+                    removed.push(format!("EXIF {}", name));
+                    false
+                }
+                None => true,
+            })
+            .collect()
+    };
+
+    let mut ifd0 = scrub(parsed.read_ifd(ifd0_offset, 0), &mut removed);
+    // 0x0202 is the length belonging to JPEGInterchangeFormat
+    ifd0.retain(|f| f.tag != 0x0202);
+    for field in ifd0.iter_mut() {
+        if field.tag == TAG_EXIF_IFD
+            && let ExifValue::Ifd(sub) = &mut field.value
+        {
+            *sub = scrub(std::mem::take(sub), &mut removed);
+        }
+    }
+    if parsed.next_ifd(ifd0_offset).is_some() {
+        removed.push("EXIF thumbnail".into());
+    }
+
+    if removed.is_empty() {
+        (tiff.to_vec(), removed)
+    } else {
+        (write_exif(&ifd0, parsed.little_endian), removed)
+    }
+}
+
+/// Position of the TIFF data inside the first `Exif\0\0` APP1 segment
+fn jpeg_exif_range(data: &[u8]) -> Option<std::ops::Range<usize>> {
+    jpeg_segments(data)
+        .iter()
+        .find(|s| s.marker == 0xE1 && s.data.starts_with(b"Exif\0\0"))
+        .map(|s| s.offset + 4 + 6..s.offset + 4 + s.data.len())
+}
+
+/// TIFF body of the EXIF APP1 segment of a JPEG
+pub(crate) fn jpeg_exif(data: &[u8]) -> Option<&[u8]> {
+    jpeg_exif_range(data).map(|range| &data[range])
+}
+
+/// TIFF body of the `eXIf` chunk of a PNG
+pub(crate) fn png_exif(data: &[u8]) -> Option<&[u8]> {
+    png_chunks(data).into_iter().find(|c| &c.kind == b"eXIf").map(|c| c.data)
+}
+
+/// EXIF orientation of a JPEG or PNG, 1 when the file doesn't say
+pub(crate) fn image_orientation(data: &[u8]) -> u8 {
+    jpeg_exif(data)
+        .or_else(|| png_exif(data))
+        .and_then(exif_orientation)
+        .unwrap_or(1)
+}
+
+/// Mark a JPEG as upright after its pixels have been rotated
+pub(crate) fn reset_jpeg_orientation(data: &mut [u8]) {
+    if let Some(range) = jpeg_exif_range(data) {
+        set_exif_orientation(&mut data[range], 1);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Minimal EXIF TIFF block holding only an Orientation tag
+    pub(crate) fn orientation_tiff(orientation: u16, little_endian: bool) -> Vec<u8> {
+        let u16b = |v: u16| if little_endian { v.to_le_bytes() } else { v.to_be_bytes() };
+        let u32b = |v: u32| if little_endian { v.to_le_bytes() } else { v.to_be_bytes() };
+        let mut tiff = if little_endian { b"II*\0".to_vec() } else { b"MM\0*".to_vec() };
+        tiff.extend_from_slice(&u32b(8));
+        tiff.extend_from_slice(&u16b(1));
+        tiff.extend_from_slice(&u16b(TAG_ORIENTATION));
+        tiff.extend_from_slice(&u16b(3));
+        tiff.extend_from_slice(&u32b(1));
+        tiff.extend_from_slice(&u16b(orientation));
+        tiff.extend_from_slice(&[0, 0]);
+        tiff.extend_from_slice(&u32b(0));
+        tiff
+    }
+
+    /// Splice an EXIF APP1 segment in after SOI
+    pub(crate) fn with_exif(jpeg: &[u8], tiff: &[u8]) -> Vec<u8> {
+        let mut out = jpeg[..2].to_vec();
+        out.extend_from_slice(&[0xFF, 0xE1]);
+        out.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+        out.extend_from_slice(b"Exif\0\0");
+        out.extend_from_slice(tiff);
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
+
+    #[test]
+    fn test_orientation_round_trip() {
+        for little_endian in [true, false] {
+            let mut tiff = orientation_tiff(6, little_endian);
+            assert_eq!(exif_orientation(&tiff), Some(6));
+            assert!(set_exif_orientation(&mut tiff, 1));
+            assert_eq!(exif_orientation(&tiff), Some(1));
+        }
+        assert_eq!(exif_orientation(&orientation_tiff(9, true)), None);
+        assert_eq!(exif_orientation(b"garbage"), None);
+    }
+
+    fn ascii(tag: u16, text: &str) -> ExifField {
+        let mut bytes = text.as_bytes().to_vec();
+        bytes.push(0);
+        ExifField { tag, field_type: 2, count: bytes.len() as u32, value: ExifValue::Bytes(bytes) }
+    }
+
+    #[test]
+    fn test_exif_rebuild_and_whitelist() {
+        let ifd0 = vec![
+            ascii(0x010F, "PhoneMaker"),
+            ExifField { tag: TAG_ORIENTATION, field_type: 3, count: 1, value: ExifValue::Bytes(vec![0, 6]) },
+            ascii(0x8298, "(c) Example Corp"),
+            ExifField {
+                tag: TAG_EXIF_IFD,
+                field_type: 4,
+                count: 1,
+                value: ExifValue::Ifd(vec![ascii(0x9003, "2024:05:01 10:00:00"), ascii(0xA431, "SN12345")]),
+            },
+        ];
+        let tiff = write_exif(&ifd0, false);
+        let (parsed, little_endian) = read_exif(&tiff).unwrap();
+        assert!(!little_endian);
+        assert_eq!(parsed, ifd0);
+        assert_eq!(exif_orientation(&tiff), Some(6));
+
+        let kept = whitelist_exif(&tiff).unwrap();
+        let (parsed, _) = read_exif(&kept).unwrap();
+        assert_eq!(parsed.iter().map(|f| f.tag).collect::<Vec<_>>(), vec![0x8298, TAG_EXIF_IFD]);
+        assert_eq!(parsed[1].value, ExifValue::Ifd(vec![ascii(0x9003, "2024:05:01 10:00:00")]));
+        assert_eq!(whitelist_exif(&orientation_tiff(1, true)), None);
+    }
+
+    #[test]
+    fn test_scrub_exif() {
+        let ifd0 = vec![
+            ascii(0x010F, "PhoneMaker"),
+            ascii(0x013B, "Jane Doe"),
+            ExifField { tag: TAG_ORIENTATION, field_type: 3, count: 1, value: ExifValue::Bytes(vec![6, 0]) },
+            ExifField {
+                tag: TAG_EXIF_IFD,
+                field_type: 4,
+                count: 1,
+                value: ExifValue::Ifd(vec![
+                    ExifField { tag: 0x927C, field_type: 7, count: 6, value: ExifValue::Bytes(b"vendor".to_vec()) },
+                    ascii(0xA431, "SN12345"),
+                ]),
+            },
+            ExifField {
+                tag: TAG_GPS_IFD,
+                field_type: 4,
+                count: 1,
+                value: ExifValue::Ifd(vec![ascii(0x0001, "N")]),
+            },
+        ];
+        let (scrubbed, removed) = scrub_exif(&write_exif(&ifd0, true));
+        assert_eq!(removed, vec!["EXIF Artist", "EXIF GPS", "EXIF MakerNote", "EXIF BodySerialNumber"]);
+        assert_eq!(exif_orientation(&scrubbed), Some(6));
+        let (parsed, _) = read_exif(&scrubbed).unwrap();
+        assert_eq!(parsed.iter().map(|f| f.tag).collect::<Vec<_>>(), vec![0x010F, TAG_ORIENTATION, TAG_EXIF_IFD]);
+        assert!(!scrubbed.windows(7).any(|w| w == b"SN12345"));
+        assert!(!scrubbed.windows(8).any(|w| w == b"Jane Doe"));
+
+        // Nothing private: the block is returned as is
+        let clean = orientation_tiff(3, false);
+        assert_eq!(scrub_exif(&clean), (clean.clone(), Vec::new()));
+    }
+
+    #[test]
+    fn test_jpeg_orientation() {
+        let jpeg = b"\xff\xd8\xff\xd9".to_vec();
+        assert_eq!(image_orientation(&jpeg), 1);
+        let mut tagged = with_exif(&jpeg, &orientation_tiff(8, false));
+        assert_eq!(image_orientation(&tagged), 8);
+        reset_jpeg_orientation(&mut tagged);
+        assert_eq!(image_orientation(&tagged), 1);
+    }
+}
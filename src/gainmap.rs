@@ -0,0 +1,267 @@
+use crate::exif::Tiff;
+use crate::jpeg::jpeg_segments;
+
+const MPF_SIGNATURE: &[u8] = b"MPF\0";
+const XMP_JPEG_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// APP2 segments carrying ISO 21496-1 gain map metadata
+const ISO_GAIN_MAP_SIGNATURE: &[u8] = b"urn:iso:std:iso:ts:21496:-1\0";
+/// MPEntry tag of the MP Index IFD: one 16-byte entry per image
+const TAG_MP_ENTRY: u16 = 0xB002;
+/// MP type code of a baseline MP primary image
+const MP_PRIMARY_IMAGE: u32 = 0x0003_0000;
+
+/// Split an Ultra HDR / ISO 21496-1 JPEG into its SDR base image and the gain map
+/// JPEG stored after it. `None` for ordinary JPEGs, including multi-picture files
+/// whose second image isn't a gain map.
+pub(crate) fn split_gain_map(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let segments = jpeg_segments(data);
+    let mpf = segments
+        .iter()
+        .find(|s| s.marker == 0xE2 && s.data.starts_with(MPF_SIGNATURE))?;
+    // MPF offsets count from the TIFF header that follows the signature
+    let tiff_start = mpf.offset + 4 + MPF_SIGNATURE.len();
+    let tiff = Tiff::parse(&mpf.data[MPF_SIGNATURE.len()..])?;
+    let entry = tiff
+        .entries(tiff.ifd0_offset()?)
+        .into_iter()
+        .find(|e| e.tag == TAG_MP_ENTRY)?;
+    let entries = tiff.u32_at(entry.offset + 8)? as usize;
+
+    (1..entry.count as usize / 16).find_map(|i| {
+        let size = tiff.u32_at(entries + i * 16 + 4)? as usize;
+        let offset = tiff.u32_at(entries + i * 16 + 8)? as usize;
+        let start = tiff_start.checked_add(offset)?;
+        let gain_map = data.get(start..start.checked_add(size)?)?;
+        (gain_map.starts_with(b"\xff\xd8") && (has_gain_map_metadata(data) || has_gain_map_metadata(gain_map)))
+            .then(|| (&data[..start], gain_map))
+    })
+}
+
+/// Whether a JPEG header carries `hdrgm` XMP or ISO 21496-1 gain map metadata
+fn has_gain_map_metadata(jpeg: &[u8]) -> bool {
+    jpeg_segments(jpeg).iter().any(|s| {
+        (s.marker == 0xE1 && s.data.starts_with(XMP_JPEG_SIGNATURE) && contains(s.data, b"hdrgm"))
+            || (s.marker == 0xE2 && s.data.starts_with(ISO_GAIN_MAP_SIGNATURE))
+    })
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Reassemble a recompressed base image and gain map into one Ultra HDR JPEG.
+///
+/// Any MPF segment `primary` still carries is replaced by a fresh one pointing at
+/// the new gain map, the XMP `GainMap` directory item gets the new length (a
+/// minimal hdrgm/container XMP is added when the metadata policy removed it), and
+/// ISO 21496-1 segments of `source_primary` the policy dropped are restored.
+pub(crate) fn join_gain_map(primary: &[u8], gain_map: &[u8], source_primary: &[u8]) -> Vec<u8> {
+    let segments = jpeg_segments(primary);
+    let xmp = segments
+        .iter()
+        .find(|s| s.marker == 0xE1 && s.data.starts_with(XMP_JPEG_SIGNATURE))
+        .map(|s| String::from_utf8_lossy(&s.data[XMP_JPEG_SIGNATURE.len()..]).into_owned());
+    let xmp = with_gain_map_item(xmp.as_deref(), gain_map.len());
+
+    let mut carried: Vec<(u8, Vec<u8>)> = vec![(0xE1, [XMP_JPEG_SIGNATURE, xmp.as_bytes()].concat())];
+    if !segments.iter().any(|s| s.marker == 0xE2 && s.data.starts_with(ISO_GAIN_MAP_SIGNATURE)) {
+        carried.extend(
+            jpeg_segments(source_primary)
+                .iter()
+                .filter(|s| s.marker == 0xE2 && s.data.starts_with(ISO_GAIN_MAP_SIGNATURE))
+                .map(|s| (s.marker, s.data.to_vec())),
+        );
+    }
+
+    // Drop the old XMP and MPF segments, then append ours after the last APPn segment
+    let mut out = Vec::with_capacity(primary.len() + gain_map.len() + xmp.len() + 256);
+    out.extend_from_slice(&primary[..2]);
+    let mut body_start = 2;
+    for segment in &segments {
+        if !(0xE0..=0xEF).contains(&segment.marker) && segment.marker != 0xFE {
+            break;
+        }
+        body_start = segment.offset + 4 + segment.data.len();
+        let replaced = (segment.marker == 0xE1 && segment.data.starts_with(XMP_JPEG_SIGNATURE))
+            || (segment.marker == 0xE2 && segment.data.starts_with(MPF_SIGNATURE));
+        if !replaced {
+            out.extend_from_slice(&primary[segment.offset..body_start]);
+        }
+    }
+    for (marker, body) in &carried {
+        // An XMP packet too big for one segment is dropped like in `write_metadata`
+        if body.len() + 2 <= u16::MAX as usize {
+            out.extend_from_slice(&[0xFF, *marker]);
+            out.extend_from_slice(&(body.len() as u16 + 2).to_be_bytes());
+            out.extend_from_slice(body);
+        }
+    }
+
+    let mpf_offset = out.len();
+    let primary_size = out.len() + 4 + MPF_SIZE + (primary.len() - body_start);
+    let gain_map_offset = primary_size - (mpf_offset + 4 + MPF_SIGNATURE.len());
+    out.extend_from_slice(&[0xFF, 0xE2]);
+    out.extend_from_slice(&(MPF_SIZE as u16 + 2).to_be_bytes());
+    out.extend_from_slice(&mpf_segment(primary_size as u32, gain_map.len() as u32, gain_map_offset as u32));
+    out.extend_from_slice(&primary[body_start..]);
+    out.extend_from_slice(gain_map);
+    out
+}
+
+/// Size of the MPF APP2 body written by `mpf_segment`
+const MPF_SIZE: usize = 4 + 8 + 2 + 3 * 12 + 4 + 2 * 16;
+
+/// Big-endian MPF body listing the primary image and one gain map image
+fn mpf_segment(primary_size: u32, gain_map_size: u32, gain_map_offset: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(MPF_SIZE);
+    out.extend_from_slice(MPF_SIGNATURE);
+    out.extend_from_slice(b"MM\0*");
+    out.extend_from_slice(&8u32.to_be_bytes());
+    out.extend_from_slice(&3u16.to_be_bytes());
+    // MPFVersion "0100", NumberOfImages 2, MPEntry pointing past the IFD
+    let entries_offset = 8 + 2 + 3 * 12 + 4;
+    for (tag, field_type, count, value) in [
+        (0xB000u16, 7u16, 4u32, u32::from_be_bytes(*b"0100")),
+        (0xB001, 4, 1, 2),
+        (TAG_MP_ENTRY, 7, 32, entries_offset),
+    ] {
+        out.extend_from_slice(&tag.to_be_bytes());
+        out.extend_from_slice(&field_type.to_be_bytes());
+        out.extend_from_slice(&count.to_be_bytes());
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+    out.extend_from_slice(&0u32.to_be_bytes());
+    for (attribute, size, offset) in [(MP_PRIMARY_IMAGE, primary_size, 0), (0, gain_map_size, gain_map_offset)] {
+        out.extend_from_slice(&attribute.to_be_bytes());
+        out.extend_from_slice(&size.to_be_bytes());
+        out.extend_from_slice(&offset.to_be_bytes());
+        // No dependent images
+        out.extend_from_slice(&[0; 4]);
+    }
+    out
+}
+
+/// `rdf:Description` declaring the hdrgm version and the primary/gain map container items
+fn gain_map_description(length: usize) -> String {
+    format!(
+        "<rdf:Description rdf:about=\"\" \
+         xmlns:hdrgm=\"http://ns.adobe.com/hdr-gain-map/1.0/\" \
+         xmlns:Container=\"http://ns.google.com/photos/1.0/container/\" \
+         xmlns:Item=\"http://ns.google.com/photos/1.0/container/item/\" \
+         hdrgm:Version=\"1.0\">\
+         <Container:Directory><rdf:Seq>\
+         <rdf:li rdf:parseType=\"Resource\"><Container:Item Item:Semantic=\"Primary\" Item:Mime=\"image/jpeg\"/></rdf:li>\
+         <rdf:li rdf:parseType=\"Resource\"><Container:Item Item:Semantic=\"GainMap\" Item:Mime=\"image/jpeg\" Item:Length=\"{}\"/></rdf:li>\
+         </rdf:Seq></Container:Directory></rdf:Description>",
+        length
+    )
+}
+
+/// Point the XMP `GainMap` container item at a gain map of `length` bytes
+fn with_gain_map_item(xmp: Option<&str>, length: usize) -> String {
+    let Some(xmp) = xmp else {
+        return format!(
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\
+             <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">{}</rdf:RDF></x:xmpmeta>",
+            gain_map_description(length)
+        );
+    };
+
+    let Some(item) = xmp.find("Item:Semantic=\"GainMap\"") else {
+        // Whitelisted XMP: add our description next to the kept one
+        return match xmp.rfind("</rdf:RDF>") {
+            Some(end) => format!("{}{}{}", &xmp[..end], gain_map_description(length), &xmp[end..]),
+            None => with_gain_map_item(None, length),
+        };
+    };
+    let start = xmp[..item].rfind('<').unwrap_or(item);
+    let end = xmp[item..].find('>').map_or(xmp.len(), |e| item + e);
+    let element = &xmp[start..end];
+    let element = match element.find("Item:Length=\"") {
+        Some(attr) => {
+            let value = attr + "Item:Length=\"".len();
+            let value_end = element[value..].find('"').map_or(element.len(), |e| value + e);
+            format!("{}{}{}", &element[..value], length, &element[value_end..])
+        }
+        None => {
+            let close = if element.ends_with('/') { element.len() - 1 } else { element.len() };
+            format!("{} Item:Length=\"{}\"{}", element[..close].trim_end(), length, &element[close..])
+        }
+    };
+    format!("{}{}{}", &xmp[..start], element, &xmp[end..])
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::metadata::{write_metadata, ImageMetadata};
+    use crate::test_support::{encode, sample};
+    use image::{DynamicImage, GrayImage, ImageFormat, Luma, Rgb, RgbImage};
+
+    /// Ultra HDR JPEG: a `width`x`height` base image and a quarter-size gain map
+    pub(crate) fn ultra_hdr(width: u32, height: u32) -> Vec<u8> {
+        let base = DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 5 % 256) as u8, (y * 3 % 256) as u8, 128])
+        }));
+        let gain_map = DynamicImage::ImageLuma8(GrayImage::from_fn(width / 4, height / 4, |x, _| {
+            Luma([(x * 9 % 256) as u8])
+        }));
+        let (base, gain_map) = (encode(&base, ImageFormat::Jpeg), encode(&gain_map, ImageFormat::Jpeg));
+        let gain_map_xmp = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\
+            <rdf:Description xmlns:hdrgm=\"http://ns.adobe.com/hdr-gain-map/1.0/\" hdrgm:Version=\"1.0\" \
+            hdrgm:GainMapMax=\"2.0\" hdrgm:HDRCapacityMax=\"2.0\"/></rdf:RDF></x:xmpmeta>";
+        let gain_map = write_metadata(
+            &gain_map,
+            &ImageMetadata { xmp: Some(gain_map_xmp.as_bytes().to_vec()), ..Default::default() },
+        )
+        .unwrap();
+        join_gain_map(&base, &gain_map, &base)
+    }
+
+    #[test]
+    fn test_split_and_join_round_trip() {
+        let hdr = ultra_hdr(64, 48);
+        let (primary, gain_map) = split_gain_map(&hdr).unwrap();
+        assert_eq!(primary.len() + gain_map.len(), hdr.len());
+        assert!(has_gain_map_metadata(gain_map));
+        assert_eq!(image::load_from_memory(gain_map).unwrap().width(), 16);
+
+        let xmp = String::from_utf8_lossy(primary);
+        assert!(xmp.contains(&format!("Item:Length=\"{}\"", gain_map.len())));
+        assert!(xmp.contains("hdrgm:Version=\"1.0\""));
+
+        // Joining again replaces the MPF and XMP segments instead of duplicating them
+        let rejoined = join_gain_map(primary, gain_map, primary);
+        assert_eq!(rejoined, hdr);
+    }
+
+    #[test]
+    fn test_plain_jpeg_has_no_gain_map() {
+        let jpeg = sample(ImageFormat::Jpeg);
+        assert!(split_gain_map(&jpeg).is_none());
+
+        // An MPF second image without gain map metadata (e.g. a camera preview) is left alone
+        let hdr = ultra_hdr(32, 32);
+        let (primary, _) = split_gain_map(&hdr).unwrap();
+        let segments = jpeg_segments(primary);
+        let xmp = segments.iter().find(|s| s.data.starts_with(XMP_JPEG_SIGNATURE)).unwrap();
+        let mpf = segments.iter().find(|s| s.data.starts_with(MPF_SIGNATURE)).unwrap();
+        let mut multi = [&primary[..xmp.offset], &primary[xmp.offset + 4 + xmp.data.len()..]].concat();
+        multi.extend_from_slice(&jpeg);
+        let size_pos = mpf.offset - (4 + xmp.data.len()) + 4 + MPF_SIZE - 16 + 4;
+        multi[size_pos..size_pos + 4].copy_from_slice(&(jpeg.len() as u32).to_be_bytes());
+        assert!(split_gain_map(&multi).is_none());
+    }
+
+    #[test]
+    fn test_gain_map_item_in_whitelisted_xmp() {
+        let xmp = "<x:xmpmeta><rdf:RDF><rdf:Description dc:rights=\"x\"/></rdf:RDF></x:xmpmeta>";
+        let updated = with_gain_map_item(Some(xmp), 1234);
+        assert!(updated.contains("dc:rights=\"x\""));
+        assert!(updated.contains("Item:Length=\"1234\""));
+        assert!(updated.ends_with("</rdf:RDF></x:xmpmeta>"));
+
+        let updated = with_gain_map_item(Some(&updated), 99);
+        assert!(updated.contains("Item:Length=\"99\"") && !updated.contains("1234"));
+    }
+}
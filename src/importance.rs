@@ -0,0 +1,197 @@
+use image::{DynamicImage, GrayImage, RgbImage};
+
+use crate::analysis::glyph_blocks;
+use crate::metrics::Plane;
+
+/// Per-pixel importance, 0 (background) to 255 (must stay sharp)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportanceMap {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// Where an importance map comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportanceSource {
+    /// Falls off from the centre towards the edges, where product shots put the background
+    CenterWeighted,
+    /// Blocks that look like text
+    Text,
+    /// The more important of `CenterWeighted` and `Text` at each pixel
+    Auto,
+    /// Rectangles `[x, y, width, height]` in fractions of the image size, e.g. the
+    /// faces or product a detector found; everything else is background
+    Regions(Vec<[f32; 4]>),
+    /// A caller-computed map, resized to the image if needed
+    Map(ImportanceMap),
+}
+
+impl ImportanceMap {
+    pub fn new(width: u32, height: u32, data: Vec<u8>) -> Result<Self, String> {
+        if data.len() != width as usize * height as usize {
+            return Err(format!(
+                "Importance map of {}x{} needs {} values, got {}",
+                width,
+                height,
+                width as usize * height as usize,
+                data.len()
+            ));
+        }
+        Ok(Self { width, height, data })
+    }
+
+    /// Bilinear resize, so a coarse map can drive a full-size image
+    pub fn resized(&self, width: u32, height: u32) -> ImportanceMap {
+        if (width, height) == (self.width, self.height) {
+            return self.clone();
+        }
+        let gray = self.to_gray();
+        let resized = image::imageops::resize(&gray, width, height, image::imageops::FilterType::Triangle);
+        ImportanceMap { width, height, data: resized.into_raw() }
+    }
+
+    /// Importance of the pixel at (x, y)
+    pub fn get(&self, x: u32, y: u32) -> u8 {
+        self.data[(y * self.width + x) as usize]
+    }
+
+    fn to_gray(&self) -> GrayImage {
+        GrayImage::from_raw(self.width, self.height, self.data.clone()).expect("map size checked on construction")
+    }
+
+    /// Gaussian-soften the map so region borders don't show as seams
+    fn softened(&self, sigma: f32) -> ImportanceMap {
+        let blurred = image::imageops::blur(&self.to_gray(), sigma);
+        ImportanceMap { width: self.width, height: self.height, data: blurred.into_raw() }
+    }
+
+    fn max(&self, other: &ImportanceMap) -> ImportanceMap {
+        let data = self.data.iter().zip(&other.data).map(|(a, b)| *a.max(b)).collect();
+        ImportanceMap { width: self.width, height: self.height, data }
+    }
+}
+
+/// Importance map for `img` at its own size
+pub fn importance_map(img: &DynamicImage, source: &ImportanceSource) -> Result<ImportanceMap, String> {
+    let (width, height) = (img.width(), img.height());
+    Ok(match source {
+        ImportanceSource::CenterWeighted => center_weighted(width, height),
+        ImportanceSource::Text => text_map(img),
+        ImportanceSource::Auto => center_weighted(width, height).max(&text_map(img)),
+        ImportanceSource::Regions(regions) => regions_map(width, height, regions),
+        // The fields are public, so the size may not have been checked yet
+        ImportanceSource::Map(map) => ImportanceMap::new(map.width, map.height, map.data.clone())?.resized(width, height),
+    })
+}
+
+/// Full importance inside an ellipse covering the middle third, fading out to none at the edges
+pub(crate) fn center_weighted(width: u32, height: u32) -> ImportanceMap {
+    let mut data = Vec::with_capacity(width as usize * height as usize);
+    for y in 0..height {
+        for x in 0..width {
+            // Distance from the centre, 1.0 on the ellipse touching the image edges
+            let dx = (x as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+            let dy = (y as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+            let d = (dx * dx + dy * dy).sqrt();
+            let t = ((d - 0.35) / (0.9 - 0.35)).clamp(0.0, 1.0);
+            let falloff = 1.0 - t * t * (3.0 - 2.0 * t);
+            data.push((falloff * 255.0).round() as u8);
+        }
+    }
+    ImportanceMap { width, height, data }
+}
+
+fn regions_map(width: u32, height: u32, regions: &[[f32; 4]]) -> ImportanceMap {
+    let mut data = vec![0u8; width as usize * height as usize];
+    for &[rx, ry, rw, rh] in regions {
+        // Regions reaching past the image are cropped to it, ones wholly outside add nothing
+        let x0 = ((rx * width as f32).floor().max(0.0) as u32).min(width);
+        let y0 = ((ry * height as f32).floor().max(0.0) as u32).min(height);
+        let x1 = (((rx + rw) * width as f32).ceil() as u32).min(width);
+        let y1 = (((ry + rh) * height as f32).ceil() as u32).min(height);
+        for y in y0..y1 {
+            data[(y * width + x0) as usize..(y * width + x1.max(x0)) as usize].fill(255);
+        }
+    }
+    let sigma = (width.min(height) as f32 / 64.0).max(1.0);
+    ImportanceMap { width, height, data }.softened(sigma)
+}
+
+/// Text blocks and their neighbours, so whole words stay sharp and not just the strokes
+fn text_map(img: &DynamicImage) -> ImportanceMap {
+    let (width, height) = (img.width(), img.height());
+    let luma = Plane::luma(img, [255, 255, 255]);
+    let blocks = glyph_blocks(&luma);
+    let (columns, rows) = (luma.width / 8, luma.height / 8);
+    let is_text = |bx: usize, by: usize| blocks[by * columns + bx] == Some(true);
+
+    let mut data = vec![0u8; width as usize * height as usize];
+    for by in 0..rows {
+        for bx in 0..columns {
+            let near_text = (by.saturating_sub(1)..(by + 2).min(rows))
+                .any(|ny| (bx.saturating_sub(1)..(bx + 2).min(columns)).any(|nx| is_text(nx, ny)));
+            if near_text {
+                for y in by * 8..by * 8 + 8 {
+                    data[y * width as usize + bx * 8..y * width as usize + bx * 8 + 8].fill(255);
+                }
+            }
+        }
+    }
+    ImportanceMap { width, height, data }.softened(4.0)
+}
+
+/// Blend each pixel towards a blurred copy by how unimportant it is. JPEG has one
+/// quantization table per component, so lowering quality in the background is done
+/// by removing the detail there before encoding: smooth blocks cost few bits.
+pub(crate) fn smooth_unimportant(img: &RgbImage, map: &ImportanceMap, sigma: f32) -> RgbImage {
+    if sigma <= 0.0 {
+        return img.clone();
+    }
+    let blurred = image::imageops::blur(img, sigma);
+    let mut out = img.clone();
+    for ((x, y, pixel), soft) in out.enumerate_pixels_mut().zip(blurred.pixels()) {
+        let weight = map.get(x, y) as u32;
+        for c in 0..3 {
+            pixel[c] = ((pixel[c] as u32 * weight + soft[c] as u32 * (255 - weight) + 127) / 255) as u8;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_importance_maps() {
+        let map = center_weighted(64, 48);
+        assert_eq!(map.get(32, 24), 255);
+        assert_eq!(map.get(0, 0), 0);
+        assert!(map.get(48, 24) < 255 && map.get(48, 24) > map.get(63, 24));
+
+        let map = regions_map(100, 100, &[[0.5, 0.5, 0.25, 0.25]]);
+        assert_eq!(map.get(62, 62), 255);
+        assert_eq!(map.get(10, 10), 0);
+
+        let map = regions_map(100, 100, &[[2.0, 0.9, 0.1, 0.1], [0.9, -1.0, 0.5, 1.05], [-0.5, -0.5, 0.1, 0.1]]);
+        assert_eq!(map.get(98, 1), 255);
+        assert_eq!(map.get(50, 50), 0);
+
+        let coarse = ImportanceMap::new(2, 1, vec![0, 255]).unwrap();
+        let map = coarse.resized(8, 4);
+        assert!(map.get(0, 0) < 64 && map.get(7, 3) > 192);
+        assert!(ImportanceMap::new(2, 2, vec![0; 3]).is_err());
+    }
+
+    #[test]
+    fn test_smooth_unimportant_keeps_important_pixels() {
+        let img = RgbImage::from_fn(16, 16, |x, y| image::Rgb([if (x + y) % 2 == 0 { 0 } else { 255 }; 3]));
+        let data = (0..256).map(|i| if i % 16 < 8 { 255 } else { 0 }).collect();
+        let map = ImportanceMap::new(16, 16, data).unwrap();
+        let out = smooth_unimportant(&img, &map, 2.0);
+        assert_eq!(out.get_pixel(2, 5), img.get_pixel(2, 5));
+        let smoothed = out.get_pixel(12, 5)[0];
+        assert!((64..192).contains(&smoothed));
+    }
+}
@@ -1,7 +1,14 @@
-use jni::objects::{JByteArray, JClass};
-use jni::sys::{jbyteArray, jint};
+use jni::objects::{JByteArray, JClass, JString, JValue};
+use jni::sys::{jbyteArray, jint, jobject, jstring};
 use jni::JNIEnv;
-use crate::opts::{ImageType, Compression};
+use crate::jpeg::estimate_jpeg_quality;
+use crate::opts::{ImageType, Compression, CompressionOptions};
+use crate::probe::probe_image;
+use crate::metrics::compare_images;
+use crate::analysis::analyze_image;
+
+/// Java class returned by the options-based entry points
+const COMPRESS_RESULT_CLASS: &str = "cn/lihongjie/image/CompressResult";
 
 /// JNI function for FastImageUtils.compressNative()
 /// 
@@ -19,6 +26,7 @@ use crate::opts::{ImageType, Compression};
 /// # Returns
 /// * Compressed image data as byte array, or null if compression fails
 #[unsafe(no_mangle)]
+#[allow(clippy::needless_borrows_for_generic_args)]
 pub extern "system" fn Java_cn_lihongjie_image_FastImageUtils_compressNative(
     mut env: JNIEnv,
     _class: JClass,
@@ -85,11 +93,237 @@ pub extern "system" fn Java_cn_lihongjie_image_FastImageUtils_compressNative(
     }
 }
 
+/// A failure the JNI entry points report to Java as an exception
+#[derive(Debug, PartialEq)]
+enum JavaError {
+    /// `java/lang/IllegalArgumentException`: the caller passed bad input
+    IllegalArgument(String),
+    /// `java/lang/RuntimeException`: the work itself failed
+    Runtime(String),
+}
+
+impl JavaError {
+    fn throw(self, env: &mut JNIEnv) {
+        let (class, message) = match self {
+            JavaError::IllegalArgument(message) => ("java/lang/IllegalArgumentException", message),
+            JavaError::Runtime(message) => ("java/lang/RuntimeException", message),
+        };
+        let _ = env.throw_new(class, message);
+    }
+}
+
+/// Read a Java byte array, reporting failure as a `RuntimeException`
+fn read_bytes(env: &mut JNIEnv, array: &JByteArray) -> Result<Vec<u8>, JavaError> {
+    env.convert_byte_array(array)
+        .map_err(|e| JavaError::Runtime(format!("Failed to read input image data: {}", e)))
+}
+
+/// Return a `key=value` summary as a Java string, or throw and return null
+fn summary_to_java(env: &mut JNIEnv, summary: Result<String, JavaError>, what: &str) -> jstring {
+    let summary = match summary {
+        Ok(summary) => summary,
+        Err(e) => {
+            e.throw(env);
+            return std::ptr::null_mut();
+        }
+    };
+    match env.new_string(summary) {
+        Ok(summary) => summary.into_raw(),
+        Err(e) => {
+            let _ = env.throw_new(
+                "java/lang/RuntimeException",
+                format!("Failed to create {} result: {}", what, e)
+            );
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Compressed bytes and `info` lines for `compressWithOptionsNative`
+fn compress_with_options_result(data: &[u8], spec: &str) -> Result<(Vec<u8>, String), JavaError> {
+    let options: CompressionOptions = spec.parse().map_err(JavaError::IllegalArgument)?;
+    if data.is_empty() {
+        return Err(JavaError::IllegalArgument("Input image data cannot be empty".into()));
+    }
+    let output = ImageType::compress_with_options(data, &options)
+        .map_err(|e| JavaError::Runtime(format!("Image compression failed: {}", e)))?;
+    let info = output.summary();
+    Ok((output.data, info))
+}
+
+/// Result of `estimateJpegQualityNative`: the quality, or -1 when it can't be estimated
+fn estimate_quality_result(data: &[u8]) -> jint {
+    estimate_jpeg_quality(data).map_or(-1, |quality| quality as jint)
+}
+
+/// `info` lines of `probeNative`
+fn probe_result(data: &[u8]) -> Result<String, JavaError> {
+    probe_image(data).map(|info| info.summary()).map_err(JavaError::IllegalArgument)
+}
+
+/// `info` lines of `compareImagesNative`
+fn compare_result(reference: &[u8], distorted: &[u8]) -> Result<String, JavaError> {
+    compare_images(reference, distorted)
+        .map(|metrics| metrics.summary())
+        .map_err(JavaError::IllegalArgument)
+}
+
+/// `info` lines of `analyzeNative`
+fn analyze_result(data: &[u8]) -> Result<String, JavaError> {
+    analyze_image(data).map(|analysis| analysis.summary()).map_err(JavaError::IllegalArgument)
+}
+
+/// JNI function for FastImageUtils.compressWithOptionsNative()
+///
+/// Same as `compressNative`, but takes an options string
+/// (`quality=80;background=#ffffff;alpha=warn;size_guard=0.05`) and returns a
+/// `CompressResult(byte[] data, String info)`. `info` holds `key=value` lines
+/// (`java.util.Properties` format); `original_returned=true` means `data` is the
+/// untouched input because recompression didn't make it smaller.
+///
+/// # Returns
+/// * `CompressResult` object, or null if compression fails
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_cn_lihongjie_image_FastImageUtils_compressWithOptionsNative(
+    mut env: JNIEnv,
+    _class: JClass,
+    image_bytes: JByteArray,
+    options: JString,
+) -> jobject {
+    let spec: String = match env.get_string(&options) {
+        Ok(spec) => spec.into(),
+        Err(e) => {
+            let _ = env.throw_new(
+                "java/lang/RuntimeException",
+                format!("Failed to read options: {}", e)
+            );
+            return std::ptr::null_mut();
+        }
+    };
+
+    let result = read_bytes(&mut env, &image_bytes)
+        .and_then(|input_data| compress_with_options_result(&input_data, &spec));
+    match result {
+        Ok((data, info)) => new_compress_result(&mut env, &data, &info),
+        Err(e) => {
+            e.throw(&mut env);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// JNI function for FastImageUtils.estimateJpegQualityNative()
+///
+/// Estimates the quality factor a JPEG was saved with from its DQT tables,
+/// without decoding any pixels.
+///
+/// # Returns
+/// * Estimated quality (1-100), or -1 if the data is not a JPEG with quantization tables
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_cn_lihongjie_image_FastImageUtils_estimateJpegQualityNative(
+    mut env: JNIEnv,
+    _class: JClass,
+    image_bytes: JByteArray,
+) -> jint {
+    match read_bytes(&mut env, &image_bytes) {
+        Ok(data) => estimate_quality_result(&data),
+        Err(e) => {
+            e.throw(&mut env);
+            -1
+        }
+    }
+}
+
+/// JNI function for FastImageUtils.probeNative()
+///
+/// Reads format, size, bit depth, alpha, frame count, orientation, ICC presence
+/// and (for JPEG) the estimated quality from the headers, without decoding pixels.
+///
+/// # Returns
+/// * `key=value` lines (`java.util.Properties` format), or null if the format is not recognised
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_cn_lihongjie_image_FastImageUtils_probeNative(
+    mut env: JNIEnv,
+    _class: JClass,
+    image_bytes: JByteArray,
+) -> jstring {
+    let summary = read_bytes(&mut env, &image_bytes).and_then(|data| probe_result(&data));
+    summary_to_java(&mut env, summary, "probe")
+}
+
+/// JNI function for FastImageUtils.compareImagesNative()
+///
+/// Decodes a reference image and a compressed (or otherwise altered) version of
+/// it and measures PSNR, SSIM, MS-SSIM and DSSIM. A differently sized image is
+/// resized to the reference first.
+///
+/// # Returns
+/// * `key=value` lines (`java.util.Properties` format), or null if either image can't be decoded
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_cn_lihongjie_image_FastImageUtils_compareImagesNative(
+    mut env: JNIEnv,
+    _class: JClass,
+    reference_bytes: JByteArray,
+    distorted_bytes: JByteArray,
+) -> jstring {
+    let summary = read_bytes(&mut env, &reference_bytes).and_then(|reference| {
+        let distorted = read_bytes(&mut env, &distorted_bytes)?;
+        compare_result(&reference, &distorted)
+    });
+    summary_to_java(&mut env, summary, "metrics")
+}
+
+/// JNI function for FastImageUtils.analyzeNative()
+///
+/// Classifies the content as photo, graphic, screenshot or document and reports
+/// the statistics behind it and the recommended output format. Passing
+/// `format=content` to compressWithOptionsNative() applies the recommendation.
+///
+/// # Returns
+/// * `key=value` lines (`java.util.Properties` format), or null if the image can't be decoded
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_cn_lihongjie_image_FastImageUtils_analyzeNative(
+    mut env: JNIEnv,
+    _class: JClass,
+    image_bytes: JByteArray,
+) -> jstring {
+    let summary = read_bytes(&mut env, &image_bytes).and_then(|data| analyze_result(&data));
+    summary_to_java(&mut env, summary, "analysis")
+}
+
+/// Build a `CompressResult(byte[] data, String info)`, throwing on failure
+fn new_compress_result(env: &mut JNIEnv, data: &[u8], info: &str) -> jobject {
+    let result = env.byte_array_from_slice(data).and_then(|array| {
+        let info = env.new_string(info)?;
+        env.new_object(
+            COMPRESS_RESULT_CLASS,
+            "([BLjava/lang/String;)V",
+            &[JValue::Object(&array), JValue::Object(&info)],
+        )
+    });
+
+    match result {
+        Ok(object) => object.into_raw(),
+        Err(e) => {
+            // A pending Java exception (e.g. NoClassDefFoundError) takes precedence
+            if !env.exception_check().unwrap_or(false) {
+                let _ = env.throw_new(
+                    "java/lang/RuntimeException",
+                    format!("Failed to create CompressResult: {}", e)
+                );
+            }
+            std::ptr::null_mut()
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::sample;
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn test_jni_compress_function_exists() {
         // This test verifies that the JNI function compiles correctly
         // Real testing requires a JVM environment with actual image data
@@ -113,5 +347,64 @@ mod tests {
         assert!(function_name.starts_with("Java_"));
         assert!(function_name.contains("FastImageUtils"));
         assert!(function_name.contains("compressNative"));
+        assert!(COMPRESS_RESULT_CLASS.starts_with("cn/lihongjie/image/"));
+    }
+
+    fn info_value<'a>(info: &'a str, key: &str) -> Option<&'a str> {
+        info.lines().find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+    }
+
+    #[test]
+    fn test_compress_with_options_result() {
+        let png = sample(image::ImageFormat::Png);
+        let (data, info) = compress_with_options_result(&png, "quality=60;format=jpeg").unwrap();
+        assert!(data.starts_with(b"\xff\xd8"));
+        assert_eq!(info_value(&info, "mime_type"), Some("image/jpeg"));
+        assert_eq!(info_value(&info, "size"), Some(data.len().to_string().as_str()));
+        assert_eq!(info_value(&info, "input_size"), Some(png.len().to_string().as_str()));
+
+        assert!(matches!(
+            compress_with_options_result(&png, "quality=101"),
+            Err(JavaError::IllegalArgument(_))
+        ));
+        assert!(matches!(compress_with_options_result(&[], "quality=60"), Err(JavaError::IllegalArgument(_))));
+        assert!(matches!(
+            compress_with_options_result(b"not an image", "quality=60"),
+            Err(JavaError::Runtime(e)) if e.starts_with("Image compression failed")
+        ));
+    }
+
+    #[test]
+    fn test_estimate_quality_result() {
+        let jpeg = crate::opts::do_jpeg_compression(&sample(image::ImageFormat::Png), 60).unwrap();
+        assert_eq!(estimate_quality_result(&jpeg), estimate_jpeg_quality(&jpeg).unwrap() as jint);
+        assert_eq!(estimate_quality_result(&sample(image::ImageFormat::Png)), -1);
+    }
+
+    #[test]
+    fn test_probe_result() {
+        let info = probe_result(&sample(image::ImageFormat::Png)).unwrap();
+        assert_eq!(info_value(&info, "format"), Some("png"));
+        assert_eq!(info_value(&info, "width"), Some("48"));
+        assert_eq!(info_value(&info, "height"), Some("32"));
+        assert!(matches!(probe_result(b"not an image"), Err(JavaError::IllegalArgument(_))));
+    }
+
+    #[test]
+    fn test_compare_result() {
+        let png = sample(image::ImageFormat::Png);
+        let info = compare_result(&png, &png).unwrap();
+        assert_eq!(info_value(&info, "dssim"), Some("0.000000"));
+        assert_eq!(info_value(&info, "resized"), Some("false"));
+        assert!(matches!(compare_result(&png, b"not an image"), Err(JavaError::IllegalArgument(_))));
+    }
+
+    #[test]
+    fn test_analyze_result() {
+        let info = analyze_result(&sample(image::ImageFormat::Png)).unwrap();
+        assert!(info_value(&info, "class").is_some());
+        assert!(info_value(&info, "recommended_format").is_some());
+        assert_eq!(info_value(&info, "has_alpha"), Some("false"));
+        assert!(matches!(analyze_result(b"not an image"), Err(JavaError::IllegalArgument(_))));
     }
 }
@@ -0,0 +1,289 @@
+use mozjpeg::qtable::{AnnexK_Luma, NRobidoux};
+
+/// Natural (row-major) position of each coefficient in JPEG zigzag order
+pub(crate) const ZIGZAG_TO_NATURAL: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27,
+    20, 13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58,
+    59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// A marker segment from the JPEG header
+pub(crate) struct JpegSegment<'a> {
+    /// Marker code, e.g. 0xDB for DQT
+    pub marker: u8,
+    /// Offset of the 0xFF byte that starts the marker
+    pub offset: usize,
+    /// Segment payload, without the marker and length bytes
+    pub data: &'a [u8],
+}
+
+/// Walk the header segments of a JPEG, stopping after SOS (or at the first malformed segment)
+pub(crate) fn jpeg_segments(data: &[u8]) -> Vec<JpegSegment<'_>> {
+    let mut segments = Vec::new();
+    if !data.starts_with(b"\xff\xd8") {
+        return segments;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            break;
+        }
+        let marker = data[pos + 1];
+        // Fill bytes and standalone markers carry no length
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            pos += 2;
+            continue;
+        }
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        if length < 2 || pos + 2 + length > data.len() {
+            break;
+        }
+        segments.push(JpegSegment {
+            marker,
+            offset: pos,
+            data: &data[pos + 4..pos + 2 + length],
+        });
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        pos += 2 + length;
+    }
+    segments
+}
+
+/// Colour model of the samples stored in a JPEG
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum JpegColorModel {
+    Grayscale,
+    YCbCr,
+    Rgb,
+    Cmyk,
+    Ycck,
+}
+
+/// Detect the stored colour model from the SOF component count and the
+/// JFIF / Adobe APP14 markers, following libjpeg's rules
+pub(crate) fn jpeg_color_model(data: &[u8]) -> Option<JpegColorModel> {
+    let segments = jpeg_segments(data);
+    let components = segments
+        .iter()
+        .find(|s| is_sof_marker(s.marker))
+        .and_then(|s| s.data.get(5).copied())?;
+    let has_jfif = segments
+        .iter()
+        .any(|s| s.marker == 0xE0 && s.data.starts_with(b"JFIF\0"));
+    let adobe_transform = jpeg_adobe_transform(data);
+
+    match components {
+        1 => Some(JpegColorModel::Grayscale),
+        3 if has_jfif => Some(JpegColorModel::YCbCr),
+        3 if adobe_transform == Some(0) => Some(JpegColorModel::Rgb),
+        3 => Some(JpegColorModel::YCbCr),
+        4 if adobe_transform == Some(2) => Some(JpegColorModel::Ycck),
+        4 => Some(JpegColorModel::Cmyk),
+        _ => None,
+    }
+}
+
+/// Transform flag of the Adobe APP14 marker (0 = none, 1 = YCbCr, 2 = YCCK).
+/// Photoshop writes CMYK/YCCK samples inverted whenever this marker is present.
+pub(crate) fn jpeg_adobe_transform(data: &[u8]) -> Option<u8> {
+    jpeg_segments(data)
+        .iter()
+        .find(|s| s.marker == 0xEE && s.data.len() >= 12 && s.data.starts_with(b"Adobe"))
+        .map(|s| s.data[11])
+}
+
+/// Reassemble the ICC profile split across `ICC_PROFILE` APP2 segments
+pub(crate) fn jpeg_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks: Vec<(u8, &[u8])> = jpeg_segments(data)
+        .iter()
+        .filter(|s| s.marker == 0xE2 && s.data.len() > 14 && s.data.starts_with(b"ICC_PROFILE\0"))
+        .map(|s| (s.data[12], &s.data[14..]))
+        .collect();
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|(sequence, _)| *sequence);
+    Some(chunks.into_iter().flat_map(|(_, chunk)| chunk.iter().copied()).collect())
+}
+
+/// SOF0-SOF15, excluding DHT (C4), JPG (C8) and DAC (CC)
+pub(crate) fn is_sof_marker(marker: u8) -> bool {
+    (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC)
+}
+
+/// Quantization tables (in natural order) defined by the DQT segments, indexed by table id
+pub(crate) fn jpeg_quant_tables(data: &[u8]) -> [Option<[u16; 64]>; 4] {
+    let mut tables = [None; 4];
+    for segment in jpeg_segments(data).iter().filter(|s| s.marker == 0xDB) {
+        let mut body = segment.data;
+        while let Some(&pq_tq) = body.first() {
+            let sixteen_bit = pq_tq >> 4 != 0;
+            let id = (pq_tq & 0x0F) as usize;
+            let size = if sixteen_bit { 128 } else { 64 };
+            if id > 3 || body.len() < 1 + size {
+                break;
+            }
+            let mut table = [0u16; 64];
+            for (k, &natural) in ZIGZAG_TO_NATURAL.iter().enumerate() {
+                table[natural] = if sixteen_bit {
+                    u16::from_be_bytes([body[1 + 2 * k], body[2 + 2 * k]])
+                } else {
+                    body[1 + k] as u16
+                };
+            }
+            tables[id] = Some(table);
+            body = &body[1 + size..];
+        }
+    }
+    tables
+}
+
+/// Id of the quantization table the first SOF component (luma, or gray) uses
+fn luma_table_id(data: &[u8]) -> Option<usize> {
+    let segments = jpeg_segments(data);
+    let sof = segments.iter().find(|s| is_sof_marker(s.marker))?;
+    // P, Y, X, Nf, then C, H/V, Tq for each component
+    sof.data.get(8).map(|&tq| tq as usize)
+}
+
+/// Estimate the quality factor (1-100) a JPEG was saved with.
+///
+/// The luminance DQT table (the one the first SOF component refers to) is fitted
+/// against the libjpeg quality scaling of the Annex K table, the scale libjpeg,
+/// ImageMagick and most tools report. Returns `None` when the data has no frame
+/// header or luma table.
+pub fn estimate_jpeg_quality(data: &[u8]) -> Option<u8> {
+    let tables = jpeg_quant_tables(data);
+    let luma = tables.get(luma_table_id(data)?)?.as_ref()?;
+    Some(fit_annex_k_quality(luma))
+}
+
+/// Annex K quality whose luma table is closest to what the encoder writes at `quality`.
+///
+/// mozjpeg scales a different base table (N. Robidoux's) than Annex K, so its
+/// quality numbers can't be compared with [`estimate_jpeg_quality`] directly.
+pub(crate) fn encoder_annex_k_quality(quality: u8) -> u8 {
+    let table = libjpeg_scaled_table(&base_table(&NRobidoux), quality.max(1), 255);
+    fit_annex_k_quality(&table)
+}
+
+/// Quality whose Annex K luma table is closest to `luma`
+fn fit_annex_k_quality(luma: &[u16; 64]) -> u8 {
+    // Baseline files keep every entry in 8 bits, extended ones may go up to 32767
+    let max_entry = if luma.iter().all(|&q| q <= 255) { 255 } else { 32767 };
+    let base = base_table(&AnnexK_Luma);
+    (1..=100u8)
+        .min_by_key(|&quality| {
+            libjpeg_scaled_table(&base, quality, max_entry)
+                .iter()
+                .zip(luma.iter())
+                .map(|(&expected, &actual)| (expected as i64 - actual as i64).unsigned_abs())
+                .sum::<u64>()
+        })
+        .unwrap_or(100)
+}
+
+fn base_table(table: &mozjpeg::qtable::QTable) -> [u16; 64] {
+    // QTable keeps its coefficients private; read them back through the pointer it exposes
+    let coeffs = unsafe { std::slice::from_raw_parts(table.as_ptr(), 64) };
+    let mut base = [0u16; 64];
+    for (b, &c) in base.iter_mut().zip(coeffs) {
+        *b = c as u16;
+    }
+    base
+}
+
+/// The table `jpeg_set_quality(quality)` derives from `base`
+fn libjpeg_scaled_table(base: &[u16; 64], quality: u8, max_entry: u32) -> [u16; 64] {
+    let scale = if quality < 50 { 5000 / quality as u32 } else { 200 - 2 * quality as u32 };
+    let mut table = [0u16; 64];
+    for (t, &b) in table.iter_mut().zip(base.iter()) {
+        *t = ((b as u32 * scale + 50) / 100).clamp(1, max_entry) as u16;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opts::do_jpeg_compression;
+    use crate::test_support::sample;
+    use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+
+    fn sample_jpeg(quality: u8) -> Vec<u8> {
+        do_jpeg_compression(&sample(ImageFormat::Png), quality).unwrap()
+    }
+
+    #[test]
+    fn test_segments_stop_at_sos() {
+        let jpeg = sample_jpeg(80);
+        let segments = jpeg_segments(&jpeg);
+        assert!(segments.iter().any(|s| s.marker == 0xDB));
+        assert_eq!(segments.last().map(|s| s.marker), Some(0xDA));
+        assert!(jpeg_segments(b"not a jpeg").is_empty());
+    }
+
+    #[test]
+    fn test_color_model_and_icc() {
+        let jpeg = sample_jpeg(80);
+        assert_eq!(jpeg_color_model(&jpeg), Some(JpegColorModel::YCbCr));
+        assert_eq!(jpeg_icc_profile(&jpeg), None);
+
+        // Two ICC_PROFILE chunks, stored out of order
+        let mut with_icc = jpeg[..2].to_vec();
+        for (sequence, body) in [(2u8, &b"-world"[..]), (1, &b"hello"[..])] {
+            let mut segment = b"ICC_PROFILE\0".to_vec();
+            segment.extend_from_slice(&[sequence, 2]);
+            segment.extend_from_slice(body);
+            with_icc.extend_from_slice(&[0xFF, 0xE2]);
+            with_icc.extend_from_slice(&((segment.len() + 2) as u16).to_be_bytes());
+            with_icc.extend_from_slice(&segment);
+        }
+        with_icc.extend_from_slice(&jpeg[2..]);
+        assert_eq!(jpeg_icc_profile(&with_icc).as_deref(), Some(&b"hello-world"[..]));
+    }
+
+    #[test]
+    fn test_estimate_quality_of_mozjpeg_output() {
+        // Reported on the Annex K scale, which is where the encoder's quality maps to
+        for quality in [30, 60, 80, 95] {
+            let estimate = estimate_jpeg_quality(&sample_jpeg(quality)).unwrap();
+            assert_eq!(estimate, encoder_annex_k_quality(quality), "q={}", quality);
+        }
+        assert!(encoder_annex_k_quality(30) < encoder_annex_k_quality(60));
+        assert!(encoder_annex_k_quality(60) < encoder_annex_k_quality(95));
+    }
+
+    #[test]
+    fn test_estimate_quality_uses_sof_luma_table() {
+        // Annex K q50 as table 0, referenced by the second component only;
+        // the first component uses table 1, an Annex K q90 table
+        let q50 = libjpeg_scaled_table(&base_table(&AnnexK_Luma), 50, 255);
+        let q90 = libjpeg_scaled_table(&base_table(&AnnexK_Luma), 90, 255);
+        let mut jpeg = b"\xff\xd8".to_vec();
+        for (id, table) in [(0u8, q50), (1, q90)] {
+            jpeg.extend_from_slice(&[0xFF, 0xDB, 0, 67, id]);
+            jpeg.extend(ZIGZAG_TO_NATURAL.iter().map(|&natural| table[natural] as u8));
+        }
+        jpeg.extend_from_slice(&[0xFF, 0xC0, 0, 14, 8, 0, 16, 0, 16, 2, 1, 0x11, 1, 2, 0x11, 0]);
+        jpeg.extend_from_slice(b"\xff\xd9");
+        assert_eq!(estimate_jpeg_quality(&jpeg), Some(90));
+    }
+
+    #[test]
+    fn test_estimate_quality_of_annex_k_tables() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, Rgb([90, 120, 200])));
+        let mut jpeg = Vec::new();
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 50);
+        img.write_with_encoder(encoder).unwrap();
+        assert_eq!(estimate_jpeg_quality(&jpeg), Some(50));
+        assert_eq!(estimate_jpeg_quality(b"\x89PNG\r\n\x1a\n"), None);
+    }
+}
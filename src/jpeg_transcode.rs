@@ -0,0 +1,255 @@
+use std::mem;
+use std::os::raw::{c_int, c_ulong};
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+
+use mozjpeg_sys::*;
+
+use crate::exif::reset_jpeg_orientation;
+
+// `catch_libjpeg` can only turn libjpeg's fatal errors into `Err` if they unwind
+#[cfg(panic = "abort")]
+compile_error!("fast-image must be built with panic = \"unwind\": libjpeg errors are reported by unwinding");
+
+/// A libjpeg decompressor/compressor pair for one coefficient-domain transcode.
+///
+/// Boxed so the error managers and the `jpeg_mem_dest` output pointers stay at a
+/// fixed address; dropping it (also while unwinding from a libjpeg error) releases
+/// everything libjpeg allocated.
+struct Transcoder {
+    src: jpeg_decompress_struct,
+    dst: jpeg_compress_struct,
+    src_err: jpeg_error_mgr,
+    dst_err: jpeg_error_mgr,
+    out_buffer: *mut u8,
+    out_size: c_ulong,
+}
+
+impl Transcoder {
+    unsafe fn new() -> Box<Self> {
+        unsafe {
+            let mut t: Box<Self> = Box::new(mem::zeroed());
+            for err in [&mut t.src_err, &mut t.dst_err] {
+                jpeg_std_error(err);
+                err.error_exit = Some(unwind_error_exit);
+                err.emit_message = Some(silence_message);
+            }
+            t.src.common.err = &mut t.src_err;
+            t.dst.common.err = &mut t.dst_err;
+            jpeg_CreateDecompress(&mut t.src, JPEG_LIB_VERSION, mem::size_of::<jpeg_decompress_struct>());
+            jpeg_CreateCompress(&mut t.dst, JPEG_LIB_VERSION, mem::size_of::<jpeg_compress_struct>());
+            t
+        }
+    }
+}
+
+impl Drop for Transcoder {
+    fn drop(&mut self) {
+        unsafe {
+            jpeg_destroy_compress(&mut self.dst);
+            jpeg_destroy_decompress(&mut self.src);
+            if !self.out_buffer.is_null() {
+                libc::free(self.out_buffer.cast());
+            }
+        }
+    }
+}
+
+unsafe extern "C-unwind" fn unwind_error_exit(cinfo: &mut jpeg_common_struct) {
+    let message = unsafe {
+        let err = &*cinfo.err;
+        match err.format_message {
+            Some(format_message) => {
+                // libjpeg writes the message into the buffer despite the `&` in the binding
+                let format_message = mem::transmute::<
+                    unsafe extern "C-unwind" fn(&mut jpeg_common_struct, &[u8; 80]),
+                    unsafe extern "C-unwind" fn(&mut jpeg_common_struct, &mut [u8; 80]),
+                >(format_message);
+                let mut buffer = [0u8; 80];
+                format_message(cinfo, &mut buffer);
+                let end = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+                String::from_utf8_lossy(&buffer[..end]).into_owned()
+            }
+            None => format!("code {}", err.msg_code),
+        }
+    };
+    // resume_unwind skips the panic hook, so nothing is printed
+    panic::resume_unwind(Box::new(format!("libjpeg error: {}", message)));
+}
+
+unsafe extern "C-unwind" fn silence_message(_cinfo: &mut jpeg_common_struct, _level: c_int) {}
+
+/// Run a libjpeg operation, turning its fatal errors into `Err`
+pub(crate) fn catch_libjpeg<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "libjpeg error".into())
+    })
+}
+
+/// Losslessly optimize a JPEG without decoding it to pixels (jpegtran-style).
+///
+/// The quantized DCT coefficients are copied unchanged and re-written with
+/// optimized Huffman tables and progressive scans, so the decoded image is
+/// bit-identical to the input. With `strip_metadata` all APPn/COM segments are
+/// dropped, otherwise they are copied through.
+pub fn do_jpeg_lossless_optimization(data: &[u8], strip_metadata: bool) -> Result<Vec<u8>, String> {
+    do_jpeg_lossless_transform(data, strip_metadata, 1, false)
+}
+
+/// The jpegtran transform that undoes an EXIF orientation
+fn orientation_transform(orientation: u8) -> JXFORM_CODE {
+    match orientation {
+        2 => JXFORM_CODE_JXFORM_FLIP_H,
+        3 => JXFORM_CODE_JXFORM_ROT_180,
+        4 => JXFORM_CODE_JXFORM_FLIP_V,
+        5 => JXFORM_CODE_JXFORM_TRANSPOSE,
+        6 => JXFORM_CODE_JXFORM_ROT_90,
+        7 => JXFORM_CODE_JXFORM_TRANSVERSE,
+        8 => JXFORM_CODE_JXFORM_ROT_270,
+        _ => JXFORM_CODE_JXFORM_NONE,
+    }
+}
+
+/// [`do_jpeg_lossless_optimization`] that also rotates/flips the image upright
+/// according to an EXIF `orientation` (1-8), still in the coefficient domain.
+///
+/// Partial MCUs at the right/bottom edge can't be moved losslessly. With `trim`
+/// they are dropped (as `jpegtran -trim`), otherwise such images are rejected.
+/// A copied EXIF block has its Orientation reset to 1.
+pub fn do_jpeg_lossless_transform(
+    data: &[u8],
+    strip_metadata: bool,
+    orientation: u8,
+    trim: bool,
+) -> Result<Vec<u8>, String> {
+    if !data.starts_with(b"\xff\xd8") {
+        return Err("Failed to optimize JPEG: input is not a JPEG image".into());
+    }
+
+    let copy_option = if strip_metadata {
+        JCOPY_OPTION_JCOPYOPT_NONE
+    } else {
+        JCOPY_OPTION_JCOPYOPT_ALL
+    };
+    let transform = orientation_transform(orientation);
+
+    let mut output = catch_libjpeg(|| unsafe {
+        let mut t = Transcoder::new();
+        let t = &mut *t;
+
+        jpeg_mem_src(&mut t.src, data.as_ptr(), data.len() as c_ulong);
+        jcopy_markers_setup(&mut t.src, copy_option);
+        jpeg_read_header(&mut t.src, boolean::from(true));
+
+        let mut info: jpeg_transform_info = mem::zeroed();
+        info.transform = transform;
+        info.perfect = boolean::from(!trim);
+        info.trim = boolean::from(trim);
+        if jtransform_request_workspace(&mut t.src, &mut info) == 0 {
+            return Err("image size is not a multiple of the MCU size".to_string());
+        }
+
+        // Read source file as DCT coefficients
+        let src_coef_arrays = jpeg_read_coefficients(&mut t.src);
+
+        // Same sampling, quantization tables and colour space as the source
+        jpeg_copy_critical_parameters(&t.src, &mut t.dst);
+        let dst_coef_arrays = jtransform_adjust_parameters(&mut t.src, &mut t.dst, src_coef_arrays, &mut info);
+        jpeg_simple_progression(&mut t.dst);
+        t.dst.optimize_coding = boolean::from(true);
+
+        jpeg_mem_dest(&mut t.dst, &mut t.out_buffer, &mut t.out_size);
+        jpeg_write_coefficients(&mut t.dst, dst_coef_arrays);
+        jcopy_markers_execute(&mut t.src, &mut t.dst, copy_option);
+        jtransform_execute_transform(&mut t.src, &mut t.dst, src_coef_arrays, &mut info);
+
+        jpeg_finish_compress(&mut t.dst);
+        jpeg_finish_decompress(&mut t.src);
+
+        Ok(slice::from_raw_parts(t.out_buffer, t.out_size as usize).to_vec())
+    })
+    .and_then(|result| result)
+    .map_err(|e| format!("Failed to optimize JPEG: {}", e))?;
+
+    if transform != JXFORM_CODE_JXFORM_NONE {
+        reset_jpeg_orientation(&mut output);
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    fn baseline_jpeg() -> Vec<u8> {
+        // image's encoder writes baseline JPEGs with standard Huffman tables
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(96, 64, |x, y| {
+            Rgb([(x * 2) as u8, (y * 3) as u8, ((x * y) % 255) as u8])
+        }));
+        let mut jpeg = Vec::new();
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 85);
+        img.write_with_encoder(encoder).unwrap();
+        jpeg
+    }
+
+    #[test]
+    fn test_lossless_optimization_keeps_pixels() {
+        let jpeg = baseline_jpeg();
+        let optimized = do_jpeg_lossless_optimization(&jpeg, true).unwrap();
+        assert!(optimized.len() < jpeg.len());
+
+        let before = image::load_from_memory(&jpeg).unwrap().to_rgb8();
+        let after = image::load_from_memory(&optimized).unwrap().to_rgb8();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn test_lossless_optimization_metadata() {
+        let jpeg = baseline_jpeg();
+        // Splice a COM segment in after SOI
+        let mut with_comment = jpeg[..2].to_vec();
+        with_comment.extend_from_slice(b"\xff\xfe\x00\x07hello");
+        with_comment.extend_from_slice(&jpeg[2..]);
+
+        let kept = do_jpeg_lossless_optimization(&with_comment, false).unwrap();
+        assert!(kept.windows(5).any(|w| w == b"hello"));
+        let stripped = do_jpeg_lossless_optimization(&with_comment, true).unwrap();
+        assert!(!stripped.windows(5).any(|w| w == b"hello"));
+    }
+
+    #[test]
+    fn test_lossless_orientation_transform() {
+        use crate::exif::tests::{orientation_tiff, with_exif};
+        use crate::exif::image_orientation;
+
+        // 96x64 is a whole number of 16x16 MCUs, so the rotation is perfect
+        let jpeg = with_exif(&baseline_jpeg(), &orientation_tiff(6, true));
+        let rotated = do_jpeg_lossless_transform(&jpeg, false, 6, false).unwrap();
+        assert_eq!(image_orientation(&rotated), 1);
+
+        let before = image::load_from_memory(&baseline_jpeg()).unwrap().rotate90().to_rgb8();
+        let after = image::load_from_memory(&rotated).unwrap().to_rgb8();
+        assert_eq!(after.dimensions(), (64, 96));
+        let max_diff = before.as_raw().iter().zip(after.as_raw()).map(|(a, b)| a.abs_diff(*b)).max();
+        assert!(max_diff.unwrap() <= 8, "max diff {:?}", max_diff);
+
+        // 90x60 leaves partial MCUs: rejected unless trimmed
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(90, 60, Rgb([10, 200, 30])));
+        let mut odd = Vec::new();
+        img.write_with_encoder(image::codecs::jpeg::JpegEncoder::new_with_quality(&mut odd, 85)).unwrap();
+        assert!(do_jpeg_lossless_transform(&odd, true, 6, false).is_err());
+        let trimmed = do_jpeg_lossless_transform(&odd, true, 6, true).unwrap();
+        assert_eq!(image::load_from_memory(&trimmed).unwrap().to_rgb8().dimensions(), (56, 90));
+    }
+
+    #[test]
+    fn test_lossless_optimization_rejects_garbage() {
+        assert!(do_jpeg_lossless_optimization(b"not a jpeg", true).is_err());
+        assert!(do_jpeg_lossless_optimization(b"\xff\xd8\xff\xe0\x00\x10JFIF", true).is_err());
+    }
+}
@@ -1,9 +1,30 @@
 mod opts;
+mod jpeg;
+mod jpeg_transcode;
+mod color;
+mod exif;
+mod metadata;
+mod probe;
+mod gainmap;
+mod metrics;
+mod analysis;
+mod importance;
+mod sweep;
+#[cfg(test)]
+mod test_support;
 
 // JNI module for Java interoperability
 pub mod jni_call;
 
 pub use opts::*;
+pub use jpeg::estimate_jpeg_quality;
+pub use jpeg_transcode::{do_jpeg_lossless_optimization, do_jpeg_lossless_transform};
+pub use metadata::MetadataPolicy;
+pub use probe::{probe_image, ImageInfo};
+pub use metrics::{compare_decoded, compare_images, psnr, ssim, ssim_to_dssim, QualityMetrics};
+pub use analysis::{analyze_decoded, analyze_image, ContentAnalysis, ContentClass};
+pub use importance::{importance_map, ImportanceMap, ImportanceSource};
+pub use sweep::{quality_sweep, sweep_summary, RatePoint};
 
 #[cfg(test)]
 mod tests {
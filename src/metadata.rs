@@ -0,0 +1,1137 @@
+use std::io::{Read, Write};
+
+use crate::color::{icc_rendering_intent, is_srgb_profile};
+use crate::exif::{png_exif, scrub_exif, set_exif_orientation, whitelist_exif};
+use crate::jpeg::{jpeg_color_model, jpeg_icc_profile, jpeg_segments, JpegColorModel};
+
+const XMP_JPEG_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const IPTC_JPEG_SIGNATURE: &[u8] = b"Photoshop 3.0\0";
+const XMP_PNG_KEYWORD: &str = "XML:com.adobe.xmp";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Which metadata survives compression
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetadataPolicy {
+    /// Drop everything; `CompressionOptions::keep_icc` still keeps the ICC profile
+    #[default]
+    Strip,
+    /// Copy every metadata block
+    Keep,
+    /// Keep copyright, artist/creator, capture date and the ICC profile only
+    Whitelist,
+    /// Keep everything except GPS location, serial numbers, owner names, maker
+    /// notes, thumbnails and unrecognised vendor segments
+    Privacy,
+}
+
+/// Metadata blocks found in a JPEG, PNG or WebP file, in container-neutral form
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ImageMetadata {
+    /// EXIF as a TIFF block (without the `Exif\0\0` prefix)
+    pub exif: Option<Vec<u8>>,
+    /// XMP packet
+    pub xmp: Option<Vec<u8>>,
+    /// ICC colour profile
+    pub icc: Option<Vec<u8>>,
+    /// Photoshop image resource block holding IPTC-IIM records (JPEG APP13)
+    pub iptc: Option<Vec<u8>>,
+    /// PNG text chunks as keyword/text pairs; JPEG comments use the keyword `Comment`
+    pub text: Vec<(String, String)>,
+    /// Any other APPn segments of a JPEG source, copied by `Keep` into JPEG output only
+    pub jpeg_segments: Vec<(u8, Vec<u8>)>,
+    /// Physical pixel size (PNG `pHYs`, JPEG JFIF density)
+    pub pixel_density: Option<PixelDensity>,
+    /// Rendering intent of a PNG `sRGB` chunk
+    pub srgb_intent: Option<u8>,
+    /// PNG `gAMA`: encoding gamma times 100000 (45455 for a 1/2.2 gamma)
+    pub gamma: Option<u32>,
+    /// PNG `cHRM`: white point and red/green/blue x,y chromaticities times 100000
+    pub chromaticities: Option<[u32; 8]>,
+}
+
+/// Pixels per unit along each axis, as in PNG `pHYs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PixelDensity {
+    pub x: u32,
+    pub y: u32,
+    /// Pixels per metre when true, otherwise only the aspect ratio is known
+    pub per_metre: bool,
+}
+
+impl PixelDensity {
+    /// Pixels per inch, when the unit is known
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        self.per_metre.then_some((self.x as f64 * 0.0254, self.y as f64 * 0.0254))
+    }
+}
+
+impl ImageMetadata {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The part of this metadata a policy lets through, plus the list of items privacy mode removed
+    pub fn filtered_with_report(&self, policy: MetadataPolicy) -> (ImageMetadata, Vec<String>) {
+        if policy == MetadataPolicy::Privacy {
+            return self.scrub_private();
+        }
+        let filtered = match policy {
+            MetadataPolicy::Strip | MetadataPolicy::Privacy => ImageMetadata::default(),
+            MetadataPolicy::Keep => self.clone(),
+            MetadataPolicy::Whitelist => ImageMetadata {
+                exif: self.exif.as_deref().and_then(whitelist_exif),
+                xmp: self.xmp.as_deref().and_then(whitelist_xmp),
+                icc: self.icc.clone(),
+                iptc: self.iptc.as_deref().and_then(whitelist_iptc),
+                text: self
+                    .text
+                    .iter()
+                    .filter(|(keyword, _)| WHITELIST_PNG_TEXT.contains(&keyword.as_str()))
+                    .cloned()
+                    .collect(),
+                jpeg_segments: Vec::new(),
+                pixel_density: self.pixel_density,
+                ..self.color_hints()
+            },
+        };
+        (filtered, Vec::new())
+    }
+
+    fn scrub_private(&self) -> (ImageMetadata, Vec<String>) {
+        let mut removed = Vec::new();
+        let exif = self.exif.as_deref().map(|exif| {
+            let (exif, exif_removed) = scrub_exif(exif);
+            removed.extend(exif_removed);
+            exif
+        });
+        let xmp = self.xmp.as_deref().and_then(|xmp| {
+            let (xmp, xmp_removed) = scrub_xmp(xmp);
+            removed.extend(xmp_removed);
+            xmp
+        });
+        let iptc = self.iptc.as_deref().and_then(|irb| {
+            let (irb, irb_removed) = scrub_irb(irb);
+            removed.extend(irb_removed);
+            irb
+        });
+        let (text, text_removed): (Vec<_>, Vec<_>) = self
+            .text
+            .iter()
+            .cloned()
+            .partition(|(keyword, _)| PRIVACY_SAFE_TEXT.contains(&keyword.as_str()));
+        removed.extend(text_removed.into_iter().map(|(keyword, _)| format!("Text {}", keyword)));
+        for (marker, _) in &self.jpeg_segments {
+            removed.push(format!("JPEG APP{} segment", marker - 0xE0));
+        }
+        let scrubbed = ImageMetadata {
+            exif,
+            xmp,
+            icc: self.icc.clone(),
+            iptc,
+            text,
+            jpeg_segments: Vec::new(),
+            pixel_density: self.pixel_density,
+            ..self.color_hints()
+        };
+        (scrubbed, removed)
+    }
+
+    /// Only what describes the colours of the pixels: ICC, sRGB, gAMA and cHRM
+    pub fn color_hints(&self) -> ImageMetadata {
+        ImageMetadata {
+            icc: self.icc.clone(),
+            srgb_intent: self.srgb_intent,
+            gamma: self.gamma,
+            chromaticities: self.chromaticities,
+            ..Default::default()
+        }
+    }
+
+    /// Gamma for imagequant's colour distance: the `gAMA` value, or 0.0 (sRGB) when absent or implausible
+    pub fn quantization_gamma(&self) -> f64 {
+        match self.gamma {
+            // iCCP and sRGB take precedence over gAMA
+            Some(gamma) if self.icc.is_none() && self.srgb_intent.is_none() && (1000..100000).contains(&gamma) => {
+                gamma as f64 / 100000.0
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Mark EXIF as upright after the pixels have been rotated
+    pub fn reset_orientation(&mut self) {
+        if let Some(exif) = self.exif.as_mut() {
+            set_exif_orientation(exif, 1);
+        }
+    }
+}
+
+/// Collect the metadata of a JPEG, PNG or WebP file
+pub(crate) fn read_metadata(data: &[u8]) -> ImageMetadata {
+    if data.starts_with(b"\xff\xd8") {
+        read_jpeg_metadata(data)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        read_png_metadata(data)
+    } else if is_webp(data) {
+        read_webp_metadata(data)
+    } else {
+        ImageMetadata::default()
+    }
+}
+
+/// Add metadata to an encoded JPEG, PNG or WebP file that carries none yet
+///
+/// An ICC profile is dropped when it describes a different colour space than the
+/// encoded image (a CMYK source decoded to RGB, colour content written as gray).
+/// sRGB profiles are compacted: a PNG gets the 1-byte `sRGB` chunk, JPEG and WebP
+/// are left untagged, which viewers already treat as sRGB.
+pub(crate) fn write_metadata(data: &[u8], metadata: &ImageMetadata) -> Result<Vec<u8>, String> {
+    let mut metadata = metadata.clone();
+    if let Some(icc) = &metadata.icc {
+        let declared = icc.get(16..20);
+        let known = matches!(declared, Some(b"RGB " | b"GRAY" | b"CMYK"));
+        if known && declared != image_color_space(data).map(|s| &s[..]) {
+            metadata.icc = None;
+        } else if is_srgb_profile(icc) {
+            metadata.srgb_intent = Some(icc_rendering_intent(icc));
+            metadata.icc = None;
+        } else {
+            // PNG forbids sRGB next to iCCP
+            metadata.srgb_intent = None;
+        }
+    }
+
+    if metadata.is_empty() {
+        Ok(data.to_vec())
+    } else if data.starts_with(b"\xff\xd8") {
+        Ok(write_jpeg_metadata(data, &metadata))
+    } else if data.starts_with(PNG_SIGNATURE) {
+        write_png_metadata(data, &metadata)
+    } else if is_webp(data) {
+        write_webp_metadata(data, &metadata)
+    } else {
+        Err("Cannot write metadata: unsupported image format".into())
+    }
+}
+
+/// ICC colour space signature matching the samples of an encoded image
+fn image_color_space(data: &[u8]) -> Option<&'static [u8; 4]> {
+    if data.starts_with(b"\xff\xd8") {
+        match jpeg_color_model(data)? {
+            JpegColorModel::Grayscale => Some(b"GRAY"),
+            JpegColorModel::YCbCr | JpegColorModel::Rgb => Some(b"RGB "),
+            JpegColorModel::Cmyk | JpegColorModel::Ycck => Some(b"CMYK"),
+        }
+    } else if data.starts_with(PNG_SIGNATURE) {
+        let ihdr = png_chunks(data).into_iter().next().filter(|c| &c.kind == b"IHDR")?;
+        match ihdr.data.get(9)? {
+            0 | 4 => Some(b"GRAY"),
+            _ => Some(b"RGB "),
+        }
+    } else if is_webp(data) {
+        Some(b"RGB ")
+    } else {
+        None
+    }
+}
+
+fn read_jpeg_metadata(data: &[u8]) -> ImageMetadata {
+    let mut metadata = ImageMetadata { icc: jpeg_icc_profile(data), ..Default::default() };
+    for segment in jpeg_segments(data) {
+        match segment.marker {
+            0xE1 if segment.data.starts_with(b"Exif\0\0") => {
+                metadata.exif.get_or_insert_with(|| segment.data[6..].to_vec());
+            }
+            0xE1 if segment.data.starts_with(XMP_JPEG_SIGNATURE) => {
+                metadata.xmp.get_or_insert_with(|| segment.data[XMP_JPEG_SIGNATURE.len()..].to_vec());
+            }
+            0xED if segment.data.starts_with(IPTC_JPEG_SIGNATURE) => {
+                metadata.iptc.get_or_insert_with(|| segment.data[IPTC_JPEG_SIGNATURE.len()..].to_vec());
+            }
+            // Already collected above
+            0xE2 if segment.data.starts_with(b"ICC_PROFILE\0") => {}
+            // JFIF and Adobe markers are written by the encoder itself; only the density is carried over
+            0xE0 if segment.data.starts_with(b"JFIF\0") => metadata.pixel_density = jfif_density(segment.data),
+            0xEE if segment.data.starts_with(b"Adobe") => {}
+            0xE0..=0xEF => metadata.jpeg_segments.push((segment.marker, segment.data.to_vec())),
+            0xFE => metadata
+                .text
+                .push(("Comment".into(), String::from_utf8_lossy(segment.data).into_owned())),
+            _ => {}
+        }
+    }
+    metadata
+}
+
+/// Density from a JFIF APP0 body: units 1 = dots per inch, 2 = dots per cm, 0 = aspect ratio
+fn jfif_density(jfif: &[u8]) -> Option<PixelDensity> {
+    let unit = *jfif.get(7)?;
+    let x = u16::from_be_bytes([*jfif.get(8)?, *jfif.get(9)?]);
+    let y = u16::from_be_bytes([*jfif.get(10)?, *jfif.get(11)?]);
+    let scale = match unit {
+        1 => 1.0 / 0.0254,
+        2 => 100.0,
+        _ => return (x != y).then_some(PixelDensity { x: x as u32, y: y as u32, per_metre: false }),
+    };
+    Some(PixelDensity { x: (x as f64 * scale).round() as u32, y: (y as f64 * scale).round() as u32, per_metre: true })
+}
+
+/// Insert metadata segments after SOI (and after the JFIF APP0 segment, which must come first)
+fn write_jpeg_metadata(data: &[u8], metadata: &ImageMetadata) -> Vec<u8> {
+    let jfif = jpeg_segments(data)
+        .first()
+        .filter(|s| s.marker == 0xE0 && s.data.starts_with(b"JFIF\0") && s.data.len() >= 12)
+        .map(|s| s.offset + 4);
+    let insert_at = jfif.map(|body| body + 14 + (data[body + 12] as usize * data[body + 13] as usize * 3)).unwrap_or(2);
+
+    let mut segments = Vec::new();
+    let mut push = |marker: u8, parts: &[&[u8]]| {
+        let length: usize = parts.iter().map(|p| p.len()).sum::<usize>() + 2;
+        // Segments are limited to 64 KiB; oversized blocks are dropped rather than corrupting the file
+        if length <= u16::MAX as usize {
+            segments.extend_from_slice(&[0xFF, marker]);
+            segments.extend_from_slice(&(length as u16).to_be_bytes());
+            for part in parts {
+                segments.extend_from_slice(part);
+            }
+        }
+    };
+
+    if let Some(exif) = &metadata.exif {
+        push(0xE1, &[b"Exif\0\0", exif]);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        push(0xE1, &[XMP_JPEG_SIGNATURE, xmp]);
+    }
+    if let Some(icc) = &metadata.icc {
+        let chunks: Vec<&[u8]> = icc.chunks(65519).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            push(0xE2, &[b"ICC_PROFILE\0", &[i as u8 + 1, chunks.len() as u8], chunk]);
+        }
+    }
+    if let Some(iptc) = &metadata.iptc {
+        push(0xED, &[IPTC_JPEG_SIGNATURE, iptc]);
+    }
+    for (marker, body) in &metadata.jpeg_segments {
+        push(*marker, &[body]);
+    }
+    for (_, comment) in metadata.text.iter().filter(|(keyword, _)| keyword == "Comment") {
+        push(0xFE, &[comment.as_bytes()]);
+    }
+
+    let mut out = Vec::with_capacity(data.len() + segments.len());
+    out.extend_from_slice(&data[..insert_at]);
+    out.extend_from_slice(&segments);
+    out.extend_from_slice(&data[insert_at..]);
+
+    // Density lives in the encoder's own JFIF header
+    if let (Some(body), Some(density)) = (jfif, metadata.pixel_density) {
+        let (unit, x, y) = match density.dpi() {
+            Some((x, y)) => (1, x.round(), y.round()),
+            None => (0, density.x as f64, density.y as f64),
+        };
+        out[body + 7] = unit;
+        out[body + 8..body + 10].copy_from_slice(&(x.clamp(1.0, 65535.0) as u16).to_be_bytes());
+        out[body + 10..body + 12].copy_from_slice(&(y.clamp(1.0, 65535.0) as u16).to_be_bytes());
+    }
+    out
+}
+
+/// A chunk of a PNG file
+pub(crate) struct PngChunk<'a> {
+    pub kind: [u8; 4],
+    /// Offset of the length field that starts the chunk
+    pub offset: usize,
+    pub data: &'a [u8],
+}
+
+/// Walk the chunks of a PNG, stopping at IEND or the first truncated chunk
+pub(crate) fn png_chunks(data: &[u8]) -> Vec<PngChunk<'_>> {
+    let mut chunks = Vec::new();
+    if !data.starts_with(PNG_SIGNATURE) {
+        return chunks;
+    }
+    let mut pos = PNG_SIGNATURE.len();
+    while pos + 12 <= data.len() {
+        let length = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let kind = [data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]];
+        let Some(body) = data.get(pos + 8..pos + 8 + length) else {
+            break;
+        };
+        chunks.push(PngChunk { kind, offset: pos, data: body });
+        if &kind == b"IEND" {
+            break;
+        }
+        pos += 12 + length;
+    }
+    chunks
+}
+
+fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    flate2::read::ZlibDecoder::new(data).read_to_end(&mut out).ok()?;
+    Some(out)
+}
+
+fn deflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data).map_err(|e| e.to_string())?;
+    encoder.finish().map_err(|e| e.to_string())
+}
+
+/// ICC profile from a PNG `iCCP` chunk
+pub(crate) fn png_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let chunk = png_chunks(data).into_iter().find(|c| &c.kind == b"iCCP")?;
+    let name_end = chunk.data.iter().position(|&b| b == 0)?;
+    // Compression method byte (0 = zlib) follows the profile name
+    inflate(chunk.data.get(name_end + 2..)?)
+}
+
+/// Keyword and text of a `tEXt`, `zTXt` or `iTXt` chunk
+fn png_text(kind: &[u8; 4], data: &[u8]) -> Option<(String, String)> {
+    let keyword_end = data.iter().position(|&b| b == 0)?;
+    let keyword = latin1(&data[..keyword_end]);
+    let rest = &data[keyword_end + 1..];
+    let text = match kind {
+        b"tEXt" => latin1(rest),
+        b"zTXt" => latin1(&inflate(rest.get(1..)?)?),
+        b"iTXt" => {
+            let (compressed, rest) = (*rest.first()? == 1, rest.get(2..)?);
+            let language_end = rest.iter().position(|&b| b == 0)?;
+            let rest = &rest[language_end + 1..];
+            let translated_end = rest.iter().position(|&b| b == 0)?;
+            let text = &rest[translated_end + 1..];
+            let text = if compressed { inflate(text)? } else { text.to_vec() };
+            String::from_utf8(text).ok()?
+        }
+        _ => return None,
+    };
+    Some((keyword, text))
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn read_png_metadata(data: &[u8]) -> ImageMetadata {
+    let mut metadata = ImageMetadata {
+        exif: png_exif(data).map(<[u8]>::to_vec),
+        icc: png_icc_profile(data),
+        ..Default::default()
+    };
+    for chunk in png_chunks(data) {
+        let be32 = |i: usize| chunk.data.get(i..i + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+        match &chunk.kind {
+            b"pHYs" if chunk.data.len() == 9 => {
+                metadata.pixel_density =
+                    Some(PixelDensity { x: be32(0).unwrap_or(0), y: be32(4).unwrap_or(0), per_metre: chunk.data[8] == 1 })
+            }
+            b"sRGB" => metadata.srgb_intent = chunk.data.first().copied(),
+            b"gAMA" => metadata.gamma = be32(0),
+            b"cHRM" if chunk.data.len() == 32 => {
+                let mut values = [0u32; 8];
+                for (i, v) in values.iter_mut().enumerate() {
+                    *v = be32(i * 4).unwrap_or(0);
+                }
+                metadata.chromaticities = Some(values);
+            }
+            _ => {}
+        }
+        if let Some((keyword, text)) = png_text(&chunk.kind, chunk.data) {
+            if keyword == XMP_PNG_KEYWORD {
+                metadata.xmp.get_or_insert(text.into_bytes());
+            } else {
+                metadata.text.push((keyword, text));
+            }
+        }
+    }
+    metadata
+}
+
+fn push_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(body);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+/// Text chunk for a keyword/text pair: `tEXt` when Latin-1 is enough, `iTXt` otherwise
+fn push_png_text(out: &mut Vec<u8>, keyword: &str, text: &str) {
+    let mut body: Vec<u8> = keyword.chars().map(|c| c as u8).collect();
+    body.push(0);
+    if text.chars().all(|c| (c as u32) < 256) && keyword != XMP_PNG_KEYWORD {
+        body.extend(text.chars().map(|c| c as u8));
+        push_png_chunk(out, b"tEXt", &body);
+    } else {
+        // Uncompressed, no language tag, no translated keyword
+        body.extend_from_slice(&[0, 0, 0, 0]);
+        body.extend_from_slice(text.as_bytes());
+        push_png_chunk(out, b"iTXt", &body);
+    }
+}
+
+/// Insert metadata chunks right after IHDR (iCCP has to precede PLTE and IDAT)
+fn write_png_metadata(data: &[u8], metadata: &ImageMetadata) -> Result<Vec<u8>, String> {
+    let ihdr = png_chunks(data)
+        .into_iter()
+        .next()
+        .filter(|c| &c.kind == b"IHDR")
+        .ok_or("Cannot write metadata: PNG has no IHDR chunk")?;
+    let insert_at = ihdr.offset + 12 + ihdr.data.len();
+
+    let mut chunks = Vec::new();
+    if let Some(intent) = metadata.srgb_intent {
+        push_png_chunk(&mut chunks, b"sRGB", &[intent]);
+    }
+    if let Some(gamma) = metadata.gamma {
+        push_png_chunk(&mut chunks, b"gAMA", &gamma.to_be_bytes());
+    }
+    if let Some(chromaticities) = metadata.chromaticities {
+        let body: Vec<u8> = chromaticities.iter().flat_map(|v| v.to_be_bytes()).collect();
+        push_png_chunk(&mut chunks, b"cHRM", &body);
+    }
+    if let Some(density) = metadata.pixel_density {
+        let mut body = density.x.to_be_bytes().to_vec();
+        body.extend_from_slice(&density.y.to_be_bytes());
+        body.push(density.per_metre as u8);
+        push_png_chunk(&mut chunks, b"pHYs", &body);
+    }
+    if let Some(icc) = &metadata.icc {
+        let mut body = b"ICC profile\0\0".to_vec();
+        body.extend_from_slice(&deflate(icc)?);
+        push_png_chunk(&mut chunks, b"iCCP", &body);
+    }
+    if let Some(exif) = &metadata.exif {
+        push_png_chunk(&mut chunks, b"eXIf", exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        push_png_text(&mut chunks, XMP_PNG_KEYWORD, &String::from_utf8_lossy(xmp));
+    }
+    for (keyword, text) in &metadata.text {
+        push_png_text(&mut chunks, keyword, text);
+    }
+
+    let mut out = Vec::with_capacity(data.len() + chunks.len());
+    out.extend_from_slice(&data[..insert_at]);
+    out.extend_from_slice(&chunks);
+    out.extend_from_slice(&data[insert_at..]);
+    Ok(out)
+}
+
+pub(crate) fn is_webp(data: &[u8]) -> bool {
+    data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP"
+}
+
+/// The chunks of a WebP RIFF container
+pub(crate) fn webp_chunks(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let kind = [data[pos], data[pos + 1], data[pos + 2], data[pos + 3]];
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]) as usize;
+        let Some(body) = data.get(pos + 8..pos + 8 + size) else {
+            break;
+        };
+        chunks.push((kind, body));
+        // Chunks are padded to an even size
+        pos += 8 + size + size % 2;
+    }
+    chunks
+}
+
+fn read_webp_metadata(data: &[u8]) -> ImageMetadata {
+    let mut metadata = ImageMetadata::default();
+    for (kind, body) in webp_chunks(data) {
+        match &kind {
+            // Some writers keep the JPEG-style prefix
+            b"EXIF" => metadata.exif = Some(body.strip_prefix(b"Exif\0\0").unwrap_or(body).to_vec()),
+            b"XMP " => metadata.xmp = Some(body.to_vec()),
+            b"ICCP" => metadata.icc = Some(body.to_vec()),
+            _ => {}
+        }
+    }
+    metadata
+}
+
+/// Canvas size and alpha flag from a simple (VP8 or VP8L) WebP bitstream chunk
+pub(crate) fn webp_canvas(kind: &[u8; 4], body: &[u8]) -> Option<(u32, u32, bool)> {
+    match kind {
+        b"VP8 " => {
+            let bytes = body.get(6..10)?;
+            let width = u16::from_le_bytes([bytes[0], bytes[1]]) & 0x3FFF;
+            let height = u16::from_le_bytes([bytes[2], bytes[3]]) & 0x3FFF;
+            Some((width as u32, height as u32, false))
+        }
+        b"VP8L" => {
+            let bits = u32::from_le_bytes(body.get(1..5)?.try_into().ok()?);
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1, bits >> 28 & 1 == 1))
+        }
+        _ => None,
+    }
+}
+
+/// Rebuild a WebP as an extended (VP8X) file carrying ICCP, EXIF and XMP chunks.
+/// IPTC, PNG text, density and gamma hints have no place in WebP and are not written.
+fn write_webp_metadata(data: &[u8], metadata: &ImageMetadata) -> Result<Vec<u8>, String> {
+    let chunks = webp_chunks(data);
+    let mut vp8x = match chunks.iter().find(|(kind, _)| kind == b"VP8X") {
+        Some((_, body)) if body.len() >= 10 => body[..10].to_vec(),
+        _ => {
+            let (width, height, alpha) = chunks
+                .iter()
+                .find_map(|(kind, body)| webp_canvas(kind, body))
+                .ok_or("Cannot write metadata: WebP has no image data")?;
+            let mut body = vec![if alpha { 0x10 } else { 0 }, 0, 0, 0];
+            body.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            body.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+            body
+        }
+    };
+    // ICC, EXIF and XMP flags
+    vp8x[0] &= !(0x20 | 0x08 | 0x04);
+    for (present, flag) in [(metadata.icc.is_some(), 0x20), (metadata.exif.is_some(), 0x08), (metadata.xmp.is_some(), 0x04)] {
+        if present {
+            vp8x[0] |= flag;
+        }
+    }
+
+    let mut body = b"WEBP".to_vec();
+    let mut push = |kind: &[u8; 4], chunk: &[u8]| {
+        body.extend_from_slice(kind);
+        body.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        body.extend_from_slice(chunk);
+        if chunk.len() % 2 == 1 {
+            body.push(0);
+        }
+    };
+    // VP8X, ICCP, image data (ANIM/ANMF/ALPH/VP8/VP8L), EXIF, XMP
+    push(b"VP8X", &vp8x);
+    if let Some(icc) = &metadata.icc {
+        push(b"ICCP", icc);
+    }
+    for (kind, chunk) in chunks.iter().filter(|(kind, _)| !matches!(kind, b"VP8X" | b"ICCP" | b"EXIF" | b"XMP ")) {
+        push(kind, chunk);
+    }
+    if let Some(exif) = &metadata.exif {
+        push(b"EXIF", exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        push(b"XMP ", xmp);
+    }
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+/// PNG text keywords kept by the whitelist (registered PNG keywords)
+const WHITELIST_PNG_TEXT: [&str; 3] = ["Author", "Copyright", "Creation Time"];
+
+/// XMP properties kept by the whitelist
+const WHITELIST_XMP: [&str; 9] = [
+    "dc:rights",
+    "dc:creator",
+    "xmpRights:Marked",
+    "xmpRights:WebStatement",
+    "xmpRights:UsageTerms",
+    "xmp:CreateDate",
+    "photoshop:DateCreated",
+    "photoshop:Credit",
+    "exif:DateTimeOriginal",
+];
+
+/// Rebuild an XMP packet holding only the whitelisted properties.
+///
+/// Properties are found by their conventional prefixes, either as elements
+/// (`<dc:rights>...</dc:rights>`) or as attributes of `rdf:Description`.
+pub(crate) fn whitelist_xmp(xmp: &[u8]) -> Option<Vec<u8>> {
+    let xmp = std::str::from_utf8(xmp).ok()?;
+    let mut properties = String::new();
+    for name in WHITELIST_XMP {
+        if let Some(element) = xmp_element(xmp, name) {
+            properties.push_str(element);
+        } else if let Some(value) = xmp_attribute(xmp, name) {
+            properties.push_str(&format!("<{0}>{1}</{0}>", name, value));
+        }
+    }
+    if properties.is_empty() {
+        return None;
+    }
+    Some(
+        format!(
+            concat!(
+                "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+                "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+                "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+                "<rdf:Description rdf:about=\"\"",
+                " xmlns:dc=\"http://purl.org/dc/elements/1.1/\"",
+                " xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"",
+                " xmlns:xmpRights=\"http://ns.adobe.com/xap/1.0/rights/\"",
+                " xmlns:photoshop=\"http://ns.adobe.com/photoshop/1.0/\"",
+                " xmlns:exif=\"http://ns.adobe.com/exif/1.0/\">",
+                "{}",
+                "</rdf:Description></rdf:RDF></x:xmpmeta>",
+                "<?xpacket end=\"r\"?>"
+            ),
+            properties
+        )
+        .into_bytes(),
+    )
+}
+
+/// `<name ...>...</name>` including the tags
+fn xmp_element<'a>(xmp: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}", name);
+    let mut search = 0;
+    while let Some(found) = xmp[search..].find(&open) {
+        let start = search + found;
+        let after = xmp[start + open.len()..].chars().next()?;
+        if after == '>' || after.is_whitespace() {
+            let close = format!("</{}>", name);
+            let end = xmp[start..].find(&close)? + start + close.len();
+            return Some(&xmp[start..end]);
+        }
+        search = start + open.len();
+    }
+    None
+}
+
+/// Value of a `name="..."` attribute
+fn xmp_attribute<'a>(xmp: &'a str, name: &str) -> Option<&'a str> {
+    let mut search = 0;
+    while let Some(found) = xmp[search..].find(name) {
+        let start = search + found;
+        let preceded_by_space = xmp[..start].chars().next_back().is_some_and(char::is_whitespace);
+        let rest = xmp[start + name.len()..].trim_start();
+        if preceded_by_space && let Some(rest) = rest.strip_prefix('=') {
+            let rest = rest.trim_start();
+            let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            let value = &rest[1..];
+            return value.find(quote).map(|end| &value[..end]);
+        }
+        search = start + name.len();
+    }
+    None
+}
+
+/// XMP properties privacy mode removes; entries ending in a capital letter are name prefixes
+const PRIVATE_XMP: [&str; 14] = [
+    "exif:GPS",
+    "dc:creator",
+    "photoshop:City",
+    "photoshop:State",
+    "photoshop:Country",
+    "Iptc4xmpCore:Location",
+    "exifEX:BodySerialNumber",
+    "exifEX:LensSerialNumber",
+    "exifEX:CameraOwnerName",
+    "aux:SerialNumber",
+    "aux:LensSerialNumber",
+    "aux:OwnerName",
+    "aux:ImageNumber",
+    "xmp:Thumbnails",
+];
+
+/// Text keywords privacy mode keeps; `Author`, `Copyright`, `Comment` (also JPEG COM),
+/// `Source` (the device) and free-form keywords can name the owner and are dropped,
+/// like EXIF Artist, XMP `dc:creator` and the IPTC by-line
+const PRIVACY_SAFE_TEXT: [&str; 3] = ["Title", "Creation Time", "Software"];
+
+/// Remove location, creator, serial number, owner and thumbnail properties from an XMP packet.
+/// A packet that isn't UTF-8 can't be searched and is dropped whole (`None`).
+pub(crate) fn scrub_xmp(xmp: &[u8]) -> (Option<Vec<u8>>, Vec<String>) {
+    // UTF-16 of ASCII is valid UTF-8 too, but full of NULs
+    let Some(text) = std::str::from_utf8(xmp).ok().filter(|text| !text.contains('\0')) else {
+        return (None, vec!["XMP (not UTF-8)".to_string()]);
+    };
+    let mut text = text.to_string();
+    let mut removed = Vec::new();
+    for pattern in PRIVATE_XMP {
+        let prefix = pattern.ends_with("GPS");
+        let mut search = 0;
+        while let Some(found) = text[search..].find(pattern) {
+            let start = search + found;
+            let name_end = text[start..]
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == ':' || c == '_' || c == '-' || c == '.'))
+                .map_or(text.len(), |end| start + end);
+            let name = text[start..name_end].to_string();
+            let before = text[..start].chars().next_back();
+            let range = if !prefix && name != pattern {
+                None
+            } else if before == Some('<') {
+                xmp_element_range(&text, start - 1, &name)
+            } else if before.is_some_and(char::is_whitespace) {
+                xmp_attribute_range(&text, start - 1, name_end)
+            } else {
+                None
+            };
+            match range {
+                Some(range) => {
+                    text.replace_range(range.clone(), "");
+                    removed.push(format!("XMP {}", name));
+                    search = range.start;
+                }
+                None => search = name_end,
+            }
+        }
+    }
+    (Some(text.into_bytes()), removed)
+}
+
+/// `<name ... />` or `<name ...>...</name>` starting at `start`
+fn xmp_element_range(xmp: &str, start: usize, name: &str) -> Option<std::ops::Range<usize>> {
+    let tag_end = xmp[start..].find('>')? + start;
+    if xmp[..tag_end].ends_with('/') {
+        return Some(start..tag_end + 1);
+    }
+    let close = format!("</{}>", name);
+    Some(start..xmp[tag_end..].find(&close)? + tag_end + close.len())
+}
+
+/// ` name="value"` with the whitespace at `start`
+fn xmp_attribute_range(xmp: &str, start: usize, name_end: usize) -> Option<std::ops::Range<usize>> {
+    let rest = xmp[name_end..].trim_start().strip_prefix('=')?.trim_start();
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value_start = xmp.len() - rest.len() + 1;
+    Some(start..xmp[value_start..].find(quote)? + value_start + 1)
+}
+
+/// Photoshop resources holding thumbnails
+const THUMBNAIL_RESOURCES: [u16; 2] = [0x0409, 0x040C];
+
+/// IPTC-IIM datasets privacy mode removes: (record, dataset, name)
+const PRIVATE_IPTC: [(u8, u8, &str); 6] = [
+    (2, 80, "By-line"),
+    (2, 90, "City"),
+    (2, 92, "Sub-location"),
+    (2, 95, "Province/State"),
+    (2, 100, "Country code"),
+    (2, 101, "Country name"),
+];
+
+/// Drop thumbnail resources and the creator and location datasets from a Photoshop
+/// resource block
+fn scrub_irb(irb: &[u8]) -> (Option<Vec<u8>>, Vec<String>) {
+    let mut kept = Vec::new();
+    let mut removed = Vec::new();
+    let mut pos = 0;
+    while pos + 12 <= irb.len() && &irb[pos..pos + 4] == b"8BIM" {
+        let id = u16::from_be_bytes([irb[pos + 4], irb[pos + 5]]);
+        let name_len = irb[pos + 6] as usize;
+        let size_at = pos + 6 + (name_len + 2) / 2 * 2;
+        let Some(size) = irb.get(size_at..size_at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        else {
+            break;
+        };
+        let end = (size_at + 4 + size + size % 2).min(irb.len());
+        if THUMBNAIL_RESOURCES.contains(&id) {
+            removed.push("IPTC thumbnail".to_string());
+        } else if id == 0x0404 {
+            let body = &irb[(size_at + 4).min(end)..(size_at + 4 + size).min(end)];
+            let records = scrub_iptc_records(body, &mut removed);
+            if !records.is_empty() {
+                kept.extend_from_slice(&irb[pos..size_at]);
+                kept.extend_from_slice(&(records.len() as u32).to_be_bytes());
+                kept.extend_from_slice(&records);
+                if records.len() % 2 == 1 {
+                    kept.push(0);
+                }
+            }
+        } else {
+            kept.extend_from_slice(&irb[pos..end]);
+        }
+        pos = end;
+    }
+    // Keep whatever couldn't be parsed rather than silently losing it
+    kept.extend_from_slice(&irb[pos..]);
+    ((!kept.is_empty()).then_some(kept), removed)
+}
+
+/// The datasets of an IPTC resource without the [`PRIVATE_IPTC`] ones
+fn scrub_iptc_records(mut records: &[u8], removed: &mut Vec<String>) -> Vec<u8> {
+    let mut kept = Vec::new();
+    while records.len() >= 5 && records[0] == 0x1C {
+        let (record, dataset) = (records[1], records[2]);
+        let length = u16::from_be_bytes([records[3], records[4]]) as usize;
+        // Extended-length datasets are never one of the private fields
+        if length & 0x8000 != 0 || records.len() < 5 + length {
+            break;
+        }
+        match PRIVATE_IPTC.iter().find(|(r, d, _)| (*r, *d) == (record, dataset)) {
+            Some((_, _, name)) => removed.push(format!("IPTC {}", name)),
+            None => kept.extend_from_slice(&records[..5 + length]),
+        }
+        records = &records[5 + length..];
+    }
+    kept.extend_from_slice(records);
+    kept
+}
+
+/// IPTC-IIM datasets kept by the whitelist: (record, dataset)
+const WHITELIST_IPTC: [(u8, u8); 7] = [
+    (1, 90),  // Coded character set
+    (2, 0),   // Record version
+    (2, 55),  // Date created
+    (2, 60),  // Time created
+    (2, 80),  // By-line
+    (2, 110), // Credit
+    (2, 116), // Copyright notice
+];
+
+/// Reduce a Photoshop resource block to one IPTC resource with the whitelisted datasets
+pub(crate) fn whitelist_iptc(irb: &[u8]) -> Option<Vec<u8>> {
+    let mut kept = Vec::new();
+    let mut pos = 0;
+    while pos + 12 <= irb.len() && &irb[pos..pos + 4] == b"8BIM" {
+        let id = u16::from_be_bytes([irb[pos + 4], irb[pos + 5]]);
+        // Pascal string name, padded to an even length
+        let name_len = irb[pos + 6] as usize;
+        let size_at = pos + 6 + (name_len + 2) / 2 * 2;
+        let size = u32::from_be_bytes(irb.get(size_at..size_at + 4)?.try_into().ok()?) as usize;
+        let body = irb.get(size_at + 4..size_at + 4 + size)?;
+        if id == 0x0404 {
+            let mut records = body;
+            while records.len() >= 5 && records[0] == 0x1C {
+                let (record, dataset) = (records[1], records[2]);
+                let length = u16::from_be_bytes([records[3], records[4]]) as usize;
+                // Extended-length datasets never carry the whitelisted fields
+                if length & 0x8000 != 0 || records.len() < 5 + length {
+                    break;
+                }
+                if WHITELIST_IPTC.contains(&(record, dataset)) {
+                    kept.extend_from_slice(&records[..5 + length]);
+                }
+                records = &records[5 + length..];
+            }
+        }
+        pos = size_at + 4 + size + size % 2;
+    }
+    if kept.is_empty() {
+        return None;
+    }
+    let mut out = b"8BIM\x04\x04\0\0".to_vec();
+    out.extend_from_slice(&(kept.len() as u32).to_be_bytes());
+    out.extend_from_slice(&kept);
+    if kept.len() % 2 == 1 {
+        out.push(0);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exif::read_exif;
+    use crate::exif::tests::orientation_tiff;
+    use crate::test_support::sample;
+    use image::ImageFormat;
+
+    const XMP: &str = concat!(
+        "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF><rdf:Description rdf:about=\"\" ",
+        "xmp:CreateDate=\"2024-05-01T10:00:00\" xmp:CreatorTool=\"Editor 1.0\">",
+        "<dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">(c) Example Corp</rdf:li></rdf:Alt></dc:rights>",
+        "<photoshop:City>Springfield</photoshop:City>",
+        "</rdf:Description></rdf:RDF></x:xmpmeta>"
+    );
+
+    fn full_metadata() -> ImageMetadata {
+        let mut iptc = b"8BIM\x04\x04\0\0".to_vec();
+        let records = b"\x1c\x02\x74\x00\x05(c) X\x1c\x02\x5a\x00\x06Berlin";
+        iptc.extend_from_slice(&(records.len() as u32).to_be_bytes());
+        iptc.extend_from_slice(records);
+        ImageMetadata {
+            exif: Some(orientation_tiff(6, true)),
+            xmp: Some(XMP.as_bytes().to_vec()),
+            icc: Some(vec![7u8; 70000]),
+            iptc: Some(iptc),
+            text: vec![("Copyright".into(), "(c) Example Corp".into()), ("Comment".into(), "hello".into())],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_jpeg_round_trip() {
+        let metadata = full_metadata();
+        let jpeg = write_metadata(&sample(ImageFormat::Jpeg), &metadata).unwrap();
+        // Still decodable, ICC split over two APP2 segments
+        image::load_from_memory(&jpeg).unwrap();
+        let read = read_metadata(&jpeg);
+        assert_eq!(read.exif, metadata.exif);
+        assert_eq!(read.xmp, metadata.xmp);
+        assert_eq!(read.icc, metadata.icc);
+        assert_eq!(read.iptc, metadata.iptc);
+        assert_eq!(read.text, vec![("Comment".to_string(), "hello".to_string())]);
+    }
+
+    #[test]
+    fn test_png_round_trip() {
+        let metadata = ImageMetadata { iptc: None, ..full_metadata() };
+        let png = write_metadata(&sample(ImageFormat::Png), &metadata).unwrap();
+        image::load_from_memory(&png).unwrap();
+        assert_eq!(read_metadata(&png), metadata);
+    }
+
+    #[test]
+    fn test_webp_round_trip() {
+        let metadata = ImageMetadata { icc: Some(vec![1, 2, 3]), iptc: None, text: Vec::new(), ..full_metadata() };
+        let webp = write_metadata(&sample(ImageFormat::WebP), &metadata).unwrap();
+        let decoded = image::load_from_memory(&webp).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (48, 32));
+        assert_eq!(read_metadata(&webp), metadata);
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let filtered = full_metadata().filtered_with_report(MetadataPolicy::Whitelist).0;
+        // Orientation alone is not whitelisted
+        assert_eq!(filtered.exif, None);
+        assert_eq!(filtered.icc, full_metadata().icc);
+        assert_eq!(filtered.text, vec![("Copyright".to_string(), "(c) Example Corp".to_string())]);
+
+        let xmp = String::from_utf8(filtered.xmp.unwrap()).unwrap();
+        assert!(xmp.contains("(c) Example Corp"));
+        assert!(xmp.contains("<xmp:CreateDate>2024-05-01T10:00:00</xmp:CreateDate>"));
+        assert!(!xmp.contains("Springfield") && !xmp.contains("Editor"));
+
+        let iptc = filtered.iptc.unwrap();
+        assert!(iptc.windows(5).any(|w| w == b"(c) X"));
+        assert!(!iptc.windows(6).any(|w| w == b"Berlin"));
+
+        assert!(full_metadata().filtered_with_report(MetadataPolicy::Strip).0.is_empty());
+        assert_eq!(full_metadata().filtered_with_report(MetadataPolicy::Keep).0, full_metadata());
+    }
+
+    #[test]
+    fn test_privacy_scrub() {
+        let xmp = concat!(
+            "<rdf:Description rdf:about=\"\" exif:GPSLatitude=\"52,31.0N\" aux:SerialNumber=\"123\" ",
+            "photoshop:City=\"Berlin\">",
+            "<exif:GPSLongitude>13,24.0E</exif:GPSLongitude><dc:rights>(c) X</dc:rights>",
+            "<dc:creator><rdf:Seq><rdf:li>Jane Doe</rdf:li></rdf:Seq></dc:creator>",
+            "<Iptc4xmpCore:Location>Mitte</Iptc4xmpCore:Location>",
+            "<xmp:Thumbnails><rdf:Alt/></xmp:Thumbnails><exif:GPSVersionID/>",
+            "</rdf:Description>"
+        );
+        // Copyright, by-line, object name, city, province/state and country name
+        let records = concat!(
+            "\x1c\x02\x74\x00\x05(c) X\x1c\x02\x50\x00\x08Jane Doe\x1c\x02\x05\x00\x06Sunset",
+            "\x1c\x02\x5a\x00\x06Berlin\x1c\x02\x5f\x00\x06Berlin\x1c\x02\x65\x00\x07Germany"
+        );
+        let mut irb = b"8BIM\x04\x0c\0\0\0\0\0\x04JPEG8BIM\x04\x04\0\0".to_vec();
+        irb.extend_from_slice(&(records.len() as u32).to_be_bytes());
+        irb.extend_from_slice(records.as_bytes());
+        let metadata = ImageMetadata {
+            xmp: Some(xmp.as_bytes().to_vec()),
+            iptc: Some(irb),
+            jpeg_segments: vec![(0xE3, b"vendor".to_vec())],
+            ..full_metadata()
+        };
+
+        let (scrubbed, removed) = metadata.filtered_with_report(MetadataPolicy::Privacy);
+        assert_eq!(
+            removed,
+            vec![
+                "XMP exif:GPSLatitude",
+                "XMP exif:GPSLongitude",
+                "XMP exif:GPSVersionID",
+                "XMP dc:creator",
+                "XMP photoshop:City",
+                "XMP Iptc4xmpCore:Location",
+                "XMP aux:SerialNumber",
+                "XMP xmp:Thumbnails",
+                "IPTC thumbnail",
+                "IPTC By-line",
+                "IPTC City",
+                "IPTC Province/State",
+                "IPTC Country name",
+                "Text Copyright",
+                "Text Comment",
+                "JPEG APP3 segment",
+            ]
+        );
+        assert_eq!(
+            String::from_utf8(scrubbed.xmp.unwrap()).unwrap(),
+            "<rdf:Description rdf:about=\"\"><dc:rights>(c) X</dc:rights></rdf:Description>"
+        );
+        // Orientation, ICC, copyright and the object name survive
+        assert_eq!(scrubbed.exif, metadata.exif);
+        assert_eq!(scrubbed.icc, metadata.icc);
+        let kept = b"\x1c\x02\x74\x00\x05(c) X\x1c\x02\x05\x00\x06Sunset";
+        let mut iptc = b"8BIM\x04\x04\0\0".to_vec();
+        iptc.extend_from_slice(&(kept.len() as u32).to_be_bytes());
+        iptc.extend_from_slice(kept);
+        iptc.push(0);
+        assert_eq!(scrubbed.iptc, Some(iptc));
+
+        // Nothing but location left: the IPTC resource goes away entirely
+        let metadata = ImageMetadata { xmp: None, ..full_metadata() };
+        let irb = b"8BIM\x04\x04\0\0\0\0\0\x0b\x1c\x02\x5a\x00\x06Berlin\0".to_vec();
+        let (scrubbed, _) = ImageMetadata { iptc: Some(irb), ..metadata }.filtered_with_report(MetadataPolicy::Privacy);
+        assert_eq!(scrubbed.iptc, None);
+    }
+
+    #[test]
+    fn test_privacy_scrub_text() {
+        let metadata = ImageMetadata {
+            text: vec![
+                ("Author".into(), "Jane Doe".into()),
+                ("Copyright".into(), "(c) Jane Doe".into()),
+                ("Comment".into(), "shot by Jane".into()),
+                ("Software".into(), "fast-image".into()),
+            ],
+            ..Default::default()
+        };
+        let (scrubbed, removed) = metadata.filtered_with_report(MetadataPolicy::Privacy);
+        assert_eq!(scrubbed.text, vec![("Software".to_string(), "fast-image".to_string())]);
+        assert_eq!(removed, vec!["Text Author", "Text Copyright", "Text Comment"]);
+
+        // A JPEG COM segment is read as a Comment and dropped the same way
+        let jpeg = write_metadata(&sample(ImageFormat::Jpeg), &metadata).unwrap();
+        let (scrubbed, _) = read_metadata(&jpeg).filtered_with_report(MetadataPolicy::Privacy);
+        assert!(scrubbed.text.is_empty());
+    }
+
+    #[test]
+    fn test_privacy_scrub_drops_unreadable_xmp() {
+        // UTF-16 XMP can't be searched for GPS fields, so none of it is kept
+        let xmp: Vec<u8> = "<exif:GPSLatitude>52,31.0N</exif:GPSLatitude>"
+            .encode_utf16()
+            .flat_map(u16::to_be_bytes)
+            .collect();
+        let metadata = ImageMetadata { xmp: Some(xmp), ..Default::default() };
+        let (scrubbed, removed) = metadata.filtered_with_report(MetadataPolicy::Privacy);
+        assert_eq!(scrubbed.xmp, None);
+        assert_eq!(removed, vec!["XMP (not UTF-8)"]);
+    }
+
+    #[test]
+    fn test_png_ancillary_chunks() {
+        let metadata = ImageMetadata {
+            pixel_density: Some(PixelDensity { x: 11811, y: 11811, per_metre: true }),
+            gamma: Some(45455),
+            chromaticities: Some([31270, 32900, 64000, 33000, 30000, 60000, 15000, 6000]),
+            ..Default::default()
+        };
+        let png = write_metadata(&sample(ImageFormat::Png), &metadata).unwrap();
+        assert_eq!(read_metadata(&png), metadata);
+        assert_eq!(metadata.quantization_gamma(), 0.45455);
+
+        // 300 dpi survives the trip into a JPEG's JFIF header and back
+        let jpeg = write_metadata(&sample(ImageFormat::Jpeg), &metadata).unwrap();
+        let density = read_metadata(&jpeg).pixel_density.unwrap();
+        let (x, y) = density.dpi().unwrap();
+        assert!((x - 300.0).abs() < 0.1 && (y - 300.0).abs() < 0.1, "{:?}", density);
+    }
+
+    #[test]
+    fn test_reset_orientation() {
+        let mut metadata = full_metadata();
+        metadata.reset_orientation();
+        let (ifd0, _) = read_exif(metadata.exif.as_deref().unwrap()).unwrap();
+        assert_eq!(crate::exif::exif_orientation(&crate::exif::write_exif(&ifd0, true)), Some(1));
+    }
+}
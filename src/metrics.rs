@@ -0,0 +1,348 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+use crate::exif::image_orientation;
+use crate::color::{decode_cmyk_jpeg, is_cmyk_jpeg};
+
+/// SSIM stabilising constants for 8-bit samples: (0.01 * 255)^2 and (0.03 * 255)^2
+const SSIM_C1: f64 = 6.5025;
+const SSIM_C2: f64 = 58.5225;
+/// 11-tap Gaussian window with sigma 1.5, as in Wang et al.
+const SSIM_RADIUS: usize = 5;
+const SSIM_SIGMA: f64 = 1.5;
+
+/// MS-SSIM weights of the five scales (Wang, Simoncelli & Bovik 2003)
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+/// Quality of a compressed image measured against its reference
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityMetrics {
+    /// Peak signal-to-noise ratio over the RGB channels in dB; infinite for identical images
+    pub psnr: f64,
+    /// Luma SSIM (1.0 = identical)
+    pub ssim: f64,
+    /// Multi-scale luma SSIM (1.0 = identical)
+    pub ms_ssim: f64,
+    /// Structural dissimilarity, `1 / SSIM - 1` (0.0 = identical)
+    pub dssim: f64,
+    /// Whether the compared image was resized to the reference's dimensions first
+    pub resized: bool,
+}
+
+impl QualityMetrics {
+    /// Metrics as `key=value` lines (`java.util.Properties` format)
+    pub fn summary(&self) -> String {
+        [
+            format!("psnr={:.4}", self.psnr),
+            format!("ssim={:.6}", self.ssim),
+            format!("ms_ssim={:.6}", self.ms_ssim),
+            format!("dssim={:.6}", self.dssim),
+            format!("resized={}", self.resized),
+        ]
+        .join("\n")
+    }
+}
+
+/// Decode two encoded images (JPEG, PNG, WebP, ...) upright and compare them
+pub fn compare_images(reference: &[u8], distorted: &[u8]) -> Result<QualityMetrics, String> {
+    compare_decoded(&decode_upright(reference)?, &decode_upright(distorted)?)
+}
+
+fn decode_upright(data: &[u8]) -> Result<DynamicImage, String> {
+    let mut img = if is_cmyk_jpeg(data) {
+        decode_cmyk_jpeg(data).map(DynamicImage::ImageRgb8)?
+    } else {
+        image::load_from_memory(data).map_err(|e| format!("Failed to decode image: {}", e))?
+    };
+    if let Some(orientation) = image::metadata::Orientation::from_exif(image_orientation(data)) {
+        img.apply_orientation(orientation);
+    }
+    Ok(img)
+}
+
+/// Compare two decoded images.
+///
+/// An image of a different size is resized (Lanczos3) to the reference's size, so
+/// a downscaled output can still be scored. When either image has alpha, both are
+/// composited onto black and onto white and the worse score of the two is kept:
+/// colour under fully transparent pixels doesn't count, alpha changes do.
+pub fn compare_decoded(reference: &DynamicImage, distorted: &DynamicImage) -> Result<QualityMetrics, String> {
+    let resized = reference.width() != distorted.width() || reference.height() != distorted.height();
+    let resized_image;
+    let distorted = if resized {
+        resized_image = distorted.resize_exact(reference.width(), reference.height(), FilterType::Lanczos3);
+        &resized_image
+    } else {
+        distorted
+    };
+
+    let backgrounds: &[[u8; 3]] = if reference.color().has_alpha() || distorted.color().has_alpha() {
+        &[[0, 0, 0], [255, 255, 255]]
+    } else {
+        &[[0, 0, 0]]
+    };
+    let mut metrics = QualityMetrics { psnr: f64::INFINITY, ssim: 1.0, ms_ssim: 1.0, dssim: 0.0, resized };
+    for &background in backgrounds {
+        let (a, b) = (Plane::luma(reference, background), Plane::luma(distorted, background));
+        let ssim = plane_ssim(&a, &b)?;
+        metrics.psnr = metrics.psnr.min(psnr(reference, distorted, background)?);
+        metrics.ssim = metrics.ssim.min(ssim);
+        metrics.ms_ssim = metrics.ms_ssim.min(plane_ms_ssim(&a, &b)?);
+        metrics.dssim = metrics.dssim.max(ssim_to_dssim(ssim));
+    }
+    Ok(metrics)
+}
+
+/// PSNR in dB over the RGB channels, transparent pixels composited onto `background`
+pub fn psnr(a: &DynamicImage, b: &DynamicImage, background: [u8; 3]) -> Result<f64, String> {
+    check_same_size(a.width() as usize, a.height() as usize, b.width() as usize, b.height() as usize)?;
+    let (a, b) = (a.to_rgba8(), b.to_rgba8());
+    let composite = |p: &image::Rgba<u8>, i: usize| {
+        let alpha = p[3] as f64 / 255.0;
+        p[i] as f64 * alpha + background[i] as f64 * (1.0 - alpha)
+    };
+    let mut squared_error = 0.0;
+    for (pa, pb) in a.pixels().zip(b.pixels()) {
+        for i in 0..3 {
+            let diff = composite(pa, i) - composite(pb, i);
+            squared_error += diff * diff;
+        }
+    }
+    let mse = squared_error / (a.len() / 4 * 3) as f64;
+    Ok(if mse == 0.0 { f64::INFINITY } else { 10.0 * (255.0 * 255.0 / mse).log10() })
+}
+
+fn check_same_size(aw: usize, ah: usize, bw: usize, bh: usize) -> Result<(), String> {
+    if (aw, ah) != (bw, bh) {
+        return Err(format!("Cannot compare a {}x{} image with a {}x{} image", aw, ah, bw, bh));
+    }
+    if aw == 0 || ah == 0 {
+        return Err("Cannot compare empty images".into());
+    }
+    Ok(())
+}
+
+/// A single-channel plane of f64 samples
+#[derive(Debug, Clone)]
+pub(crate) struct Plane {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f64>,
+}
+
+impl Plane {
+    /// Rec. 601 luma of an image composited onto `background`, 0-255
+    pub fn luma(img: &DynamicImage, background: [u8; 3]) -> Self {
+        let rgba = img.to_rgba8();
+        let bg = background.map(|c| c as f64);
+        let data = rgba
+            .pixels()
+            .map(|p| {
+                let alpha = p[3] as f64 / 255.0;
+                let c = |i: usize| p[i] as f64 * alpha + bg[i] * (1.0 - alpha);
+                0.299 * c(0) + 0.587 * c(1) + 0.114 * c(2)
+            })
+            .collect();
+        Self { width: rgba.width() as usize, height: rgba.height() as usize, data }
+    }
+
+    /// Half-size plane, each sample the mean of a 2x2 block
+    fn downsample(&self) -> Plane {
+        let (width, height) = (self.width / 2, self.height / 2);
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let at = |dx: usize, dy: usize| self.data[(2 * y + dy) * self.width + 2 * x + dx];
+                data.push((at(0, 0) + at(1, 0) + at(0, 1) + at(1, 1)) / 4.0);
+            }
+        }
+        Plane { width, height, data }
+    }
+
+    fn map2(&self, other: &Plane, f: impl Fn(f64, f64) -> f64) -> Plane {
+        let data = self.data.iter().zip(&other.data).map(|(&a, &b)| f(a, b)).collect();
+        Plane { width: self.width, height: self.height, data }
+    }
+
+    /// Separable Gaussian blur with clamped edges
+    fn blur(&self, kernel: &[f64]) -> Plane {
+        let radius = kernel.len() / 2;
+        let (w, h) = (self.width, self.height);
+        let pass = |src: &[f64], horizontal: bool| -> Vec<f64> {
+            let mut out = vec![0.0; src.len()];
+            for y in 0..h {
+                for x in 0..w {
+                    let mut sum = 0.0;
+                    for (k, weight) in kernel.iter().enumerate() {
+                        let offset = k as isize - radius as isize;
+                        let (sx, sy) = if horizontal {
+                            ((x as isize + offset).clamp(0, w as isize - 1) as usize, y)
+                        } else {
+                            (x, (y as isize + offset).clamp(0, h as isize - 1) as usize)
+                        };
+                        sum += weight * src[sy * w + sx];
+                    }
+                    out[y * w + x] = sum;
+                }
+            }
+            out
+        };
+        let data = pass(&pass(&self.data, true), false);
+        Plane { width: w, height: h, data }
+    }
+}
+
+fn gaussian_kernel() -> Vec<f64> {
+    let kernel: Vec<f64> = (0..=2 * SSIM_RADIUS)
+        .map(|i| {
+            let d = i as f64 - SSIM_RADIUS as f64;
+            (-d * d / (2.0 * SSIM_SIGMA * SSIM_SIGMA)).exp()
+        })
+        .collect();
+    let total: f64 = kernel.iter().sum();
+    kernel.into_iter().map(|k| k / total).collect()
+}
+
+/// Mean SSIM of two planes of the same size (1.0 = identical)
+pub(crate) fn plane_ssim(a: &Plane, b: &Plane) -> Result<f64, String> {
+    ssim_components(a, b).map(|(ssim, _)| ssim)
+}
+
+/// Multi-scale SSIM: contrast/structure at five dyadic scales, luminance at the
+/// coarsest. Images too small for five scales use as many as fit, with the
+/// weights renormalised.
+pub(crate) fn plane_ms_ssim(a: &Plane, b: &Plane) -> Result<f64, String> {
+    let (mut a, mut b) = (a.clone(), b.clone());
+    let scales = (1..MS_SSIM_WEIGHTS.len())
+        .take_while(|&s| a.width.min(a.height) >> s > 2 * SSIM_RADIUS)
+        .count()
+        + 1;
+    let weights = &MS_SSIM_WEIGHTS[..scales];
+    let total: f64 = weights.iter().sum();
+
+    let mut result = 1.0;
+    for (scale, weight) in weights.iter().enumerate() {
+        let (ssim, cs) = ssim_components(&a, &b)?;
+        // Negative correlation would make the power undefined
+        let term = if scale + 1 == scales { ssim } else { cs };
+        result *= term.max(0.0).powf(weight / total);
+        if scale + 1 < scales {
+            a = a.downsample();
+            b = b.downsample();
+        }
+    }
+    Ok(result)
+}
+
+/// Mean SSIM and mean contrast-structure term of two planes
+fn ssim_components(a: &Plane, b: &Plane) -> Result<(f64, f64), String> {
+    check_same_size(a.width, a.height, b.width, b.height)?;
+    let kernel = gaussian_kernel();
+    let mu_a = a.blur(&kernel);
+    let mu_b = b.blur(&kernel);
+    let aa = a.map2(a, |x, y| x * y).blur(&kernel);
+    let bb = b.map2(b, |x, y| x * y).blur(&kernel);
+    let ab = a.map2(b, |x, y| x * y).blur(&kernel);
+
+    let (mut ssim_total, mut cs_total) = (0.0, 0.0);
+    for i in 0..a.data.len() {
+        let (ma, mb) = (mu_a.data[i], mu_b.data[i]);
+        let var_a = aa.data[i] - ma * ma;
+        let var_b = bb.data[i] - mb * mb;
+        let cov = ab.data[i] - ma * mb;
+        let cs = (2.0 * cov + SSIM_C2) / (var_a + var_b + SSIM_C2);
+        cs_total += cs;
+        ssim_total += cs * (2.0 * ma * mb + SSIM_C1) / (ma * ma + mb * mb + SSIM_C1);
+    }
+    let count = a.data.len() as f64;
+    Ok((ssim_total / count, cs_total / count))
+}
+
+/// Luma SSIM of two decoded images, transparent pixels composited onto `background`
+pub fn ssim(a: &DynamicImage, b: &DynamicImage, background: [u8; 3]) -> Result<f64, String> {
+    plane_ssim(&Plane::luma(a, background), &Plane::luma(b, background))
+}
+
+/// Structural dissimilarity, `1 / SSIM - 1` (0.0 = identical)
+pub fn ssim_to_dssim(ssim: f64) -> f64 {
+    if ssim <= 0.0 { f64::INFINITY } else { 1.0 / ssim - 1.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    fn pattern(noise: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(40, 30, |x, y| {
+            let jitter = (x.wrapping_mul(2654435761) ^ y.wrapping_mul(40503)) % (noise + 1);
+            let v = ((x * 6 + y * 2) % 200 + jitter) as u8;
+            Rgb([v, v / 2, 255 - v])
+        }))
+    }
+
+    #[test]
+    fn test_ssim() {
+        let clean = pattern(0);
+        assert!((ssim(&clean, &clean, [255; 3]).unwrap() - 1.0).abs() < 1e-9);
+        let slightly = ssim(&clean, &pattern(8), [255; 3]).unwrap();
+        let heavily = ssim(&clean, &pattern(50), [255; 3]).unwrap();
+        assert!(1.0 > slightly && slightly > heavily);
+        assert!(ssim_to_dssim(heavily) > ssim_to_dssim(slightly));
+        assert!(ssim(&clean, &DynamicImage::new_rgb8(4, 4), [255; 3]).is_err());
+    }
+
+    #[test]
+    fn test_compare_images() {
+        let clean = pattern(0);
+        let metrics = compare_decoded(&clean, &clean).unwrap();
+        assert_eq!((metrics.psnr, metrics.ssim, metrics.ms_ssim, metrics.dssim), (f64::INFINITY, 1.0, 1.0, 0.0));
+
+        let noisy = compare_decoded(&clean, &pattern(20)).unwrap();
+        assert!(noisy.psnr.is_finite() && noisy.psnr > 10.0);
+        assert!(noisy.ssim < 1.0 && noisy.ms_ssim < 1.0 && noisy.dssim > 0.0);
+        assert!(!noisy.resized);
+        assert!(noisy.summary().starts_with("psnr="));
+
+        // A half-size copy is scaled back up before comparing
+        let half = clean.resize_exact(20, 15, FilterType::Triangle);
+        let scaled = compare_decoded(&clean, &half).unwrap();
+        assert!(scaled.resized && scaled.ssim > 0.5);
+
+        let mut png = Vec::new();
+        clean.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        assert_eq!(compare_images(&png, &png).unwrap().dssim, 0.0);
+        assert!(compare_images(&png, b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_compare_alpha() {
+        let opaque = RgbaImage::from_fn(32, 32, |x, _| Rgba([(x * 8) as u8, 0, 0, 255]));
+        // Colour hidden under full transparency is not a difference
+        let mut hidden = opaque.clone();
+        let mut recoloured = opaque.clone();
+        for (x, y) in [(3, 3), (20, 9)] {
+            hidden.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+            recoloured.put_pixel(x, y, Rgba([255, 255, 0, 0]));
+        }
+        let metrics = compare_decoded(&DynamicImage::ImageRgba8(hidden.clone()), &DynamicImage::ImageRgba8(recoloured)).unwrap();
+        assert_eq!(metrics.dssim, 0.0);
+        // ...but losing the transparency is
+        let metrics = compare_decoded(&DynamicImage::ImageRgba8(hidden), &DynamicImage::ImageRgba8(opaque)).unwrap();
+        assert!(metrics.dssim > 0.0);
+    }
+
+    #[test]
+    fn test_ms_ssim_small_images() {
+        let a = Plane { width: 8, height: 8, data: (0..64).map(|v| v as f64).collect() };
+        assert!((plane_ms_ssim(&a, &a).unwrap() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ssim_composites_alpha() {
+        // Fully transparent pixels compare equal to the background, whatever their colour
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::from_pixel(16, 16, Rgba([255, 0, 0, 0])));
+        let white = DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, Rgb([255, 255, 255])));
+        assert!((ssim(&transparent, &white, [255; 3]).unwrap() - 1.0).abs() < 1e-9);
+    }
+}
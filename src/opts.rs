@@ -1,4 +1,17 @@
 use std::io::Cursor;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::analysis::{analyze_pixels, ContentClass};
+use crate::color::{convert_to_srgb, decode_cmyk_jpeg, is_cmyk_jpeg, is_srgb_profile};
+use crate::jpeg::{encoder_annex_k_quality, estimate_jpeg_quality, jpeg_icc_profile, jpeg_segments};
+use crate::exif::{image_orientation, write_exif, ExifField, ExifValue, TAG_ORIENTATION};
+use crate::importance::{importance_map, smooth_unimportant, ImportanceSource};
+use crate::gainmap::{join_gain_map, split_gain_map};
+use crate::probe::probe_image;
+use crate::metrics::{compare_decoded, ssim_to_dssim};
+use crate::jpeg_transcode::do_jpeg_lossless_transform;
+use crate::metadata::{read_metadata, write_metadata, ImageMetadata, MetadataPolicy};
 
 pub enum ImageType {
     PNG,
@@ -8,6 +21,648 @@ pub enum ImageType {
 
 pub trait Compression {
     fn compress(data: &[u8], quality: u8) -> Result<Vec<u8>, String>;
+
+    fn compress_with_options(data: &[u8], options: &CompressionOptions) -> Result<CompressionOutput, String>;
+}
+
+/// What to do when a source with real transparency is encoded as JPEG
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AlphaPolicy {
+    /// Composite onto the background colour
+    #[default]
+    Flatten,
+    /// Composite onto the background colour and report a warning
+    Warn,
+    /// Refuse to drop transparency
+    Error,
+}
+
+/// How JPEG inputs are recompressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JpegMode {
+    /// Decode to pixels and re-encode at `quality`
+    #[default]
+    Reencode,
+    /// Rewrite the DCT coefficients with optimized Huffman tables and
+    /// progressive scans; pixels are untouched and `quality` is ignored
+    Lossless,
+}
+
+/// How PNG inputs with 16 bits per channel are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SixteenBitPolicy {
+    /// Round each channel to 8 bits before quantization
+    #[default]
+    Round,
+    /// Reduce to 8 bits with Floyd-Steinberg dithering before quantization,
+    /// so smooth 16-bit gradients don't band
+    Dither,
+    /// Keep full precision and write a lossless 16-bit truecolour PNG
+    Lossless,
+}
+
+/// Chroma resolution of JPEG output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChromaSubsampling {
+    /// Half-resolution chroma both ways, mozjpeg's default for photos
+    #[default]
+    Yuv420,
+    /// Full-resolution chroma, keeps coloured text and thin lines sharp
+    Yuv444,
+}
+
+impl ChromaSubsampling {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChromaSubsampling::Yuv420 => "420",
+            ChromaSubsampling::Yuv444 => "444",
+        }
+    }
+}
+
+/// Encoded formats the library can write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Palette PNG quantized by imagequant (or the grayscale/16-bit special cases)
+    Png,
+    /// Truecolour PNG with every pixel kept
+    PngLossless,
+    Jpeg,
+    /// Lossy WebP, alpha kept
+    WebP,
+    /// AVIF, alpha kept; written without metadata. Only offered by `auto` when the
+    /// linked `image` crate can decode AVIF (its `avif-native` feature), since the
+    /// quality check needs the pixels back
+    Avif,
+}
+
+impl OutputFormat {
+    /// Candidates `format=auto` considers when `allowed_formats` isn't set
+    pub const AUTO_DEFAULT: [OutputFormat; 4] =
+        [OutputFormat::Png, OutputFormat::PngLossless, OutputFormat::Jpeg, OutputFormat::WebP];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::PngLossless => "png_lossless",
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png | OutputFormat::PngLossless => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+
+    /// False for JPEG, which flattens transparency onto the background
+    pub fn supports_alpha(&self) -> bool {
+        *self != OutputFormat::Jpeg
+    }
+
+    pub(crate) fn container(&self) -> image::ImageFormat {
+        match self {
+            OutputFormat::Png | OutputFormat::PngLossless => image::ImageFormat::Png,
+            OutputFormat::Jpeg => image::ImageFormat::Jpeg,
+            OutputFormat::WebP => image::ImageFormat::WebP,
+            OutputFormat::Avif => image::ImageFormat::Avif,
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "png" => Ok(OutputFormat::Png),
+            "png_lossless" => Ok(OutputFormat::PngLossless),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "webp" => Ok(OutputFormat::WebP),
+            "avif" => Ok(OutputFormat::Avif),
+            _ => Err(format!("Unknown output format: {}", value)),
+        }
+    }
+}
+
+/// Which format the output is written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FormatChoice {
+    /// Keep the input's format (PNG or JPEG)
+    #[default]
+    Same,
+    /// Convert to the given format
+    Fixed(OutputFormat),
+    /// Encode every format in `allowed_formats` and keep the smallest one whose
+    /// DSSIM against the source is within the threshold
+    Auto,
+    /// Classify the content (photo, graphic, screenshot, document) and use the
+    /// format and chroma subsampling recommended for it
+    Content,
+}
+
+/// Settings shared by all encoders
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    /// Compression quality (0-100)
+    pub quality: u8,
+    /// RGB colour transparent pixels are composited onto for JPEG output
+    pub background: [u8; 3],
+    /// Behaviour when the input has real transparency and the output is JPEG
+    pub alpha_policy: AlphaPolicy,
+    /// Return the input unchanged unless the output is smaller by at least this
+    /// fraction of the input size (0.0 = any saving); `None` disables the guard.
+    /// Skipped, with a warning, when the pixels were converted to sRGB, rotated or resized
+    pub size_guard: Option<f32>,
+    /// Skip re-encoding a JPEG whose estimated quality is already at or below
+    /// `quality`; it is optimized losslessly instead
+    pub respect_input_quality: bool,
+    /// How JPEG inputs are recompressed
+    pub jpeg_mode: JpegMode,
+    /// Which EXIF/XMP/IPTC/ICC/text metadata is carried over to the output
+    pub metadata: MetadataPolicy,
+    /// How 16-bit PNG inputs are handled
+    pub sixteen_bit: SixteenBitPolicy,
+    /// imagequant speed/quality trade-off, 1 (slowest, best) to 10 (fastest)
+    pub speed: u8,
+    /// Upper bound on the PNG palette size (2-256)
+    pub max_colors: u32,
+    /// Floyd-Steinberg dithering strength for the PNG palette (0.0-1.0)
+    pub dithering: f32,
+    /// Ignore this many least significant bits of each channel when building
+    /// the palette (0-4); useful for output shown on 16-bit displays
+    pub posterize: u8,
+    /// Fail rather than write a PNG whose palette quality is below this (0-100)
+    pub min_quality: u8,
+    /// Rotate/flip the image upright according to its EXIF Orientation tag
+    pub auto_orient: bool,
+    /// Carry the input's ICC profile over even when `metadata` strips everything else
+    pub keep_icc: bool,
+    /// Convert pixels from the embedded ICC profile to sRGB and write no profile
+    pub convert_to_srgb: bool,
+    /// Resize by this factor (0 < scale <= 1) before encoding; ignored by lossless JPEG mode
+    pub scale: f32,
+    /// Byte budget: search for the highest quality whose output fits, instead of using `quality`
+    pub target_size: Option<usize>,
+    /// Let the `target_size` search shrink the image when lowering quality isn't enough
+    pub allow_downscale: bool,
+    /// Perceptual target: search for the lowest quality whose DSSIM against the
+    /// source is at most this (e.g. 0.001), instead of using `quality`
+    pub target_dssim: Option<f64>,
+    /// Output format, or `Auto` to pick the smallest acceptable one
+    pub format: FormatChoice,
+    /// Candidates `FormatChoice::Auto` may choose from, e.g. what the client's `Accept` header allows
+    pub allowed_formats: Vec<OutputFormat>,
+    /// Largest DSSIM an `Auto` candidate may have when `target_dssim` isn't set
+    pub max_dssim: f64,
+    /// Chroma resolution of re-encoded colour JPEGs
+    pub chroma_subsampling: ChromaSubsampling,
+    /// Which parts of the image must stay sharp: weights imagequant's palette and
+    /// lets JPEG spend fewer bits on the rest
+    pub importance: Option<ImportanceSource>,
+    /// Gaussian sigma, in pixels, the least important JPEG areas are smoothed with (0 = off)
+    pub background_blur: f32,
+    /// Move `quality` down for simple images and up for busy ones, by the
+    /// estimated complexity; ignored by the target searches, which pick the quality themselves
+    pub adaptive_quality: bool,
+    /// Largest change `adaptive_quality` makes to `quality`, in quality points
+    pub adaptive_range: u8,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            quality: 75,
+            background: [255, 255, 255],
+            alpha_policy: AlphaPolicy::Flatten,
+            size_guard: Some(0.0),
+            respect_input_quality: true,
+            jpeg_mode: JpegMode::Reencode,
+            metadata: MetadataPolicy::Strip,
+            sixteen_bit: SixteenBitPolicy::Round,
+            speed: 4,
+            max_colors: 256,
+            dithering: 1.0,
+            posterize: 0,
+            min_quality: 0,
+            auto_orient: true,
+            keep_icc: true,
+            convert_to_srgb: false,
+            scale: 1.0,
+            target_size: None,
+            allow_downscale: false,
+            target_dssim: None,
+            format: FormatChoice::Same,
+            allowed_formats: OutputFormat::AUTO_DEFAULT.to_vec(),
+            max_dssim: AUTO_FORMAT_MAX_DSSIM,
+            chroma_subsampling: ChromaSubsampling::Yuv420,
+            importance: None,
+            background_blur: 1.5,
+            adaptive_quality: false,
+            adaptive_range: 15,
+        }
+    }
+}
+
+impl CompressionOptions {
+    pub fn new(quality: u8) -> Self {
+        Self { quality, ..Default::default() }
+    }
+
+    /// The options [`Compression::compress`] (and JNI `compressNative`) run with: the
+    /// pixels are re-encoded as stored, and the new encoding is returned even when
+    /// it isn't smaller. The size guard, skipping high-quality JPEG input and EXIF
+    /// orientation are opt-in through `compress_with_options`.
+    pub fn legacy(quality: u8) -> Self {
+        Self { size_guard: None, respect_input_quality: false, auto_orient: false, ..Self::new(quality) }
+    }
+}
+
+/// Parse `key=value` pairs separated by `;` or newlines, e.g.
+/// `quality=80;background=#ffffff;alpha=warn;size_guard=0.05`.
+/// Unspecified keys keep their default value.
+impl FromStr for CompressionOptions {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut options = CompressionOptions::default();
+        for pair in spec.split([';', '\n']).map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid option '{}', expected key=value", pair))?;
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "quality" => {
+                    options.quality = value
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|q| *q <= 100)
+                        .ok_or_else(|| format!("Quality must be between 0 and 100, got: {}", value))?;
+                }
+                "background" => options.background = parse_hex_color(value)?,
+                "alpha" => {
+                    options.alpha_policy = match value {
+                        "flatten" => AlphaPolicy::Flatten,
+                        "warn" => AlphaPolicy::Warn,
+                        "error" => AlphaPolicy::Error,
+                        _ => return Err(format!("Unknown alpha policy: {}", value)),
+                    }
+                }
+                "size_guard" => {
+                    options.size_guard = match value {
+                        "off" | "none" => None,
+                        _ => Some(
+                            value
+                                .parse::<f32>()
+                                .ok()
+                                .filter(|m| (0.0..1.0).contains(m))
+                                .ok_or_else(|| format!("size_guard must be in [0, 1) or 'off', got: {}", value))?,
+                        ),
+                    }
+                }
+                "respect_input_quality" => options.respect_input_quality = parse_bool(key, value)?,
+                "jpeg_mode" => {
+                    options.jpeg_mode = match value {
+                        "reencode" => JpegMode::Reencode,
+                        "lossless" => JpegMode::Lossless,
+                        _ => return Err(format!("Unknown JPEG mode: {}", value)),
+                    }
+                }
+                "metadata" => {
+                    options.metadata = match value {
+                        "strip" => MetadataPolicy::Strip,
+                        "keep" => MetadataPolicy::Keep,
+                        "whitelist" => MetadataPolicy::Whitelist,
+                        "privacy" => MetadataPolicy::Privacy,
+                        _ => return Err(format!("Unknown metadata policy: {}", value)),
+                    }
+                }
+                // Older spelling of metadata=strip / metadata=keep
+                "strip_metadata" => {
+                    options.metadata =
+                        if parse_bool(key, value)? { MetadataPolicy::Strip } else { MetadataPolicy::Keep }
+                }
+                "sixteen_bit" => {
+                    options.sixteen_bit = match value {
+                        "round" => SixteenBitPolicy::Round,
+                        "dither" => SixteenBitPolicy::Dither,
+                        "lossless" => SixteenBitPolicy::Lossless,
+                        _ => return Err(format!("Unknown 16-bit policy: {}", value)),
+                    }
+                }
+                "auto_orient" => options.auto_orient = parse_bool(key, value)?,
+                "keep_icc" => options.keep_icc = parse_bool(key, value)?,
+                "convert_to_srgb" => options.convert_to_srgb = parse_bool(key, value)?,
+                "speed" => options.speed = parse_in_range(key, value, 1..=10)?,
+                "max_colors" => options.max_colors = parse_in_range(key, value, 2..=256)?,
+                "dithering" => options.dithering = parse_in_range(key, value, 0.0..=1.0)?,
+                "posterize" => options.posterize = parse_in_range(key, value, 0..=4)?,
+                "min_quality" => options.min_quality = parse_in_range(key, value, 0..=100)?,
+                "scale" => {
+                    options.scale = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|s| *s > 0.0 && *s <= 1.0)
+                        .ok_or_else(|| format!("scale must be in (0, 1], got: {}", value))?;
+                }
+                "target_size" => {
+                    options.target_size = match value {
+                        "off" | "none" => None,
+                        _ => Some(parse_byte_size(value)?),
+                    }
+                }
+                "allow_downscale" => options.allow_downscale = parse_bool(key, value)?,
+                "target_dssim" => options.target_dssim = Some(parse_in_range(key, value, 0.0..=1.0)?),
+                // Same target expressed as SSIM
+                "target_ssim" => {
+                    options.target_dssim = Some(ssim_to_dssim(parse_in_range(key, value, 0.5..=1.0)?))
+                }
+                "format" => {
+                    options.format = match value {
+                        "same" => FormatChoice::Same,
+                        "auto" => FormatChoice::Auto,
+                        "content" => FormatChoice::Content,
+                        _ => FormatChoice::Fixed(value.parse()?),
+                    }
+                }
+                "allowed_formats" => {
+                    options.allowed_formats = value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|f| !f.is_empty())
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()?;
+                    if options.allowed_formats.is_empty() {
+                        return Err("allowed_formats must name at least one format".into());
+                    }
+                }
+                "max_dssim" => options.max_dssim = parse_in_range(key, value, 0.0..=1.0)?,
+                "importance" => options.importance = parse_importance(value)?,
+                "background_blur" => options.background_blur = parse_in_range(key, value, 0.0..=8.0)?,
+                "adaptive_quality" => options.adaptive_quality = parse_bool(key, value)?,
+                "adaptive_range" => options.adaptive_range = parse_in_range(key, value, 0..=50)?,
+                "chroma_subsampling" => {
+                    options.chroma_subsampling = match value {
+                        "420" | "4:2:0" => ChromaSubsampling::Yuv420,
+                        "444" | "4:4:4" => ChromaSubsampling::Yuv444,
+                        _ => return Err(format!("Unknown chroma subsampling: {}", value)),
+                    }
+                }
+                _ => return Err(format!("Unknown option: {}", key)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "1" | "on" => Ok(true),
+        "false" | "0" | "off" => Ok(false),
+        _ => Err(format!("{} must be true or false, got: {}", key, value)),
+    }
+}
+
+/// `off`, `center`, `text`, `auto` or `regions:x,y,w,h|x,y,w,h` with fractions of the image size
+fn parse_importance(value: &str) -> Result<Option<ImportanceSource>, String> {
+    let source = match value {
+        "off" | "none" => return Ok(None),
+        "center" => ImportanceSource::CenterWeighted,
+        "text" => ImportanceSource::Text,
+        "auto" => ImportanceSource::Auto,
+        _ => {
+            let regions = value
+                .strip_prefix("regions:")
+                .ok_or_else(|| format!("Unknown importance source: {}", value))?;
+            let region = |spec: &str| -> Option<[f32; 4]> {
+                let numbers: Vec<f32> = spec.split(',').map(|n| n.trim().parse().ok()).collect::<Option<_>>()?;
+                let region: [f32; 4] = numbers.try_into().ok()?;
+                region.iter().all(|n| (0.0..=1.0).contains(n)).then_some(region)
+            };
+            ImportanceSource::Regions(
+                regions
+                    .split('|')
+                    .map(|spec| region(spec).ok_or_else(|| format!("Invalid importance region: {}", spec)))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+    };
+    Ok(Some(source))
+}
+
+/// Byte count with an optional `B`/`KB`/`MB` suffix (binary units), e.g. `200KB`
+fn parse_byte_size(value: &str) -> Result<usize, String> {
+    let upper = value.to_ascii_uppercase();
+    let (number, unit) = match upper.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => (upper[..i].trim(), &upper[i..]),
+        None => (upper.as_str(), "B"),
+    };
+    let multiplier = match unit {
+        "B" => 1.0,
+        "K" | "KB" | "KIB" => 1024.0,
+        "M" | "MB" | "MIB" => 1024.0 * 1024.0,
+        _ => return Err(format!("Unknown size unit in: {}", value)),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .map(|n| (n * multiplier).round())
+        .filter(|&bytes| bytes >= 1.0)
+        .map(|bytes| bytes as usize)
+        .ok_or_else(|| format!("Invalid size: {}", value))
+}
+
+fn parse_in_range<T>(key: &str, value: &str, range: std::ops::RangeInclusive<T>) -> Result<T, String>
+where
+    T: FromStr + PartialOrd + std::fmt::Display,
+{
+    value
+        .parse::<T>()
+        .ok()
+        .filter(|v| range.contains(v))
+        .ok_or_else(|| format!("{} must be between {} and {}, got: {}", key, range.start(), range.end(), value))
+}
+
+fn parse_hex_color(value: &str) -> Result<[u8; 3], String> {
+    let hex = value.trim_start_matches('#');
+    let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+        _ => Err(format!("Invalid colour '{}', expected #rrggbb", value)),
+    }
+}
+
+/// Compressed bytes plus anything the caller should know about how they were produced
+#[derive(Debug, Clone, Default)]
+pub struct CompressionOutput {
+    pub data: Vec<u8>,
+    /// True when `data` is the untouched input because recompression didn't pay off
+    pub original_returned: bool,
+    /// Estimated quality the input JPEG was saved with
+    pub input_quality: Option<u8>,
+    pub warnings: Vec<String>,
+    /// Metadata items privacy mode scrubbed, e.g. `EXIF GPS`
+    pub removed_metadata: Vec<String>,
+    /// Quality the `target_size`/`target_dssim` search or `adaptive_quality` settled on
+    pub achieved_quality: Option<u8>,
+    /// Estimated complexity (0 = flat, 1 = dense texture) `adaptive_quality` went by
+    pub complexity: Option<f64>,
+    /// Resize factor the `target_size` search had to apply
+    pub scale: Option<f32>,
+    /// DSSIM of the output against the source, measured by the `target_dssim` search
+    pub dssim: Option<f64>,
+    /// Size of the input in bytes
+    pub input_size: usize,
+    /// Encoder path that produced the output (before any size guard swap)
+    pub path: Option<CompressionPath>,
+    /// Number of colours in the PNG palette
+    pub palette_size: Option<usize>,
+    /// imagequant's own estimate of the palette quality (0-100)
+    pub quantization_quality: Option<u8>,
+    /// Mean squared error of the remapped PNG pixels, as reported by imagequant
+    pub quantization_error: Option<f64>,
+    /// Content class `format=content` based its choice on
+    pub content: Option<ContentClass>,
+    /// MIME type of `data`, e.g. for the `Content-Type` of an `Accept`-negotiated response
+    pub mime_type: Option<&'static str>,
+    /// Wall-clock time of each stage in order (`decode`, `quantize`, `encode`, ...), ending with `total`
+    pub timings: Vec<(&'static str, Duration)>,
+}
+
+/// Which encoder produced a `CompressionOutput`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionPath {
+    /// Palette PNG quantized by imagequant
+    PngPalette,
+    /// Grayscale PNG at the smallest bit depth holding every level
+    PngGrayscale,
+    /// Lossless 16-bit truecolour PNG
+    Png16,
+    /// Decoded and re-encoded with mozjpeg
+    JpegReencode,
+    /// DCT coefficients kept and rewritten losslessly
+    JpegLossless,
+    /// 8-bit truecolour PNG with every pixel kept
+    PngLossless,
+    /// Lossy WebP encoded by libwebp
+    WebP,
+    /// AVIF encoded by ravif
+    Avif,
+}
+
+impl CompressionPath {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompressionPath::PngPalette => "png_palette",
+            CompressionPath::PngGrayscale => "png_grayscale",
+            CompressionPath::Png16 => "png16",
+            CompressionPath::JpegReencode => "jpeg_reencode",
+            CompressionPath::JpegLossless => "jpeg_lossless",
+            CompressionPath::PngLossless => "png_lossless",
+            CompressionPath::WebP => "webp",
+            CompressionPath::Avif => "avif",
+        }
+    }
+}
+
+/// Collects the duration of consecutive compression stages
+struct Stopwatch {
+    lap_start: Instant,
+    timings: Vec<(&'static str, Duration)>,
+}
+
+impl Stopwatch {
+    fn new() -> Self {
+        Self { lap_start: Instant::now(), timings: Vec::new() }
+    }
+
+    /// Record the time since the previous lap under `stage`
+    fn lap(&mut self, stage: &'static str) {
+        let now = Instant::now();
+        self.timings.push((stage, now - self.lap_start));
+        self.lap_start = now;
+    }
+}
+
+impl From<Vec<u8>> for CompressionOutput {
+    fn from(data: Vec<u8>) -> Self {
+        Self { data, ..Default::default() }
+    }
+}
+
+impl CompressionOutput {
+    /// Output size as a fraction of the input size (0.25 = a quarter of the input)
+    pub fn ratio(&self) -> f64 {
+        if self.input_size == 0 { 1.0 } else { self.data.len() as f64 / self.input_size as f64 }
+    }
+
+    /// `key=value` lines describing the result, readable with `java.util.Properties`
+    pub fn summary(&self) -> String {
+        let mut lines = vec![
+            format!("size={}", self.data.len()),
+            format!("input_size={}", self.input_size),
+            format!("ratio={:.4}", self.ratio()),
+            format!("original_returned={}", self.original_returned),
+        ];
+        if let Some(mime_type) = self.mime_type {
+            lines.push(format!("mime_type={}", mime_type));
+        }
+        if let Some(path) = self.path {
+            lines.push(format!("path={}", path.name()));
+        }
+        if let Some(content) = self.content {
+            lines.push(format!("content={}", content.name()));
+        }
+        if let Some(palette_size) = self.palette_size {
+            lines.push(format!("palette_size={}", palette_size));
+        }
+        if let Some(quality) = self.quantization_quality {
+            lines.push(format!("quantization_quality={}", quality));
+        }
+        if let Some(error) = self.quantization_error {
+            lines.push(format!("quantization_error={:.4}", error));
+        }
+        for (stage, duration) in &self.timings {
+            lines.push(format!("time.{}_ms={:.3}", stage, duration.as_secs_f64() * 1000.0));
+        }
+        if let Some(quality) = self.input_quality {
+            lines.push(format!("input_quality={}", quality));
+        }
+        if let Some(quality) = self.achieved_quality {
+            lines.push(format!("achieved_quality={}", quality));
+        }
+        if let Some(scale) = self.scale {
+            lines.push(format!("scale={}", scale));
+        }
+        if let Some(dssim) = self.dssim {
+            lines.push(format!("dssim={:.6}", dssim));
+        }
+        if let Some(complexity) = self.complexity {
+            lines.push(format!("complexity={:.4}", complexity));
+        }
+        for (i, warning) in self.warnings.iter().enumerate() {
+            lines.push(format!("warning.{}={}", i, warning.replace('\n', " ")));
+        }
+        for (i, item) in self.removed_metadata.iter().enumerate() {
+            lines.push(format!("removed_metadata.{}={}", i, item));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Swap in the original bytes when the re-encoded output doesn't beat them by `min_saving`
+fn apply_size_guard(input: &[u8], mut output: CompressionOutput, min_saving: f32) -> CompressionOutput {
+    let limit = input.len() as f64 * (1.0 - min_saving as f64);
+    if output.data.len() as f64 >= limit {
+        output.data = input.to_vec();
+        output.original_returned = true;
+    }
+    output
 }
 
 impl ImageType {
@@ -24,35 +679,668 @@ impl ImageType {
 
 impl Compression for ImageType {
     fn compress(data: &[u8], quality: u8) -> Result<Vec<u8>, String> {
-        match Self::detect_type(data) {
-            Some(ImageType::PNG) => {
-                // Apply PNG compression
-                do_png_compression(data, quality)
+        Self::compress_with_options(data, &CompressionOptions::legacy(quality)).map(|output| output.data)
+    }
+
+    fn compress_with_options(data: &[u8], options: &CompressionOptions) -> Result<CompressionOutput, String> {
+        let start = Instant::now();
+        let mut output = match (options.format, options.target_size, options.target_dssim) {
+            (_, Some(_), Some(_)) => return Err("target_size and target_dssim cannot be combined".into()),
+            (_, None, None) if options.adaptive_quality => compress_adaptive(data, options),
+            // Each candidate runs its own target search
+            (FormatChoice::Auto, _, _) => compress_auto(data, options),
+            (FormatChoice::Content, _, _) => {
+                let analysis = analyze_pixels(&decode_image(data, options)?, grayscale_tolerance(data));
+                ImageType::compress_with_options(data, &analysis.apply(options))
+                    .map(|output| CompressionOutput { content: Some(analysis.class), ..output })
+            }
+            (_, Some(target), None) => compress_to_size(data, options, target),
+            (_, None, Some(target)) => compress_to_dssim(data, options, target),
+            (_, None, None) => compress_once(data, options),
+        }?;
+        output.input_size = data.len();
+        output.mime_type = image::guess_format(&output.data).ok().map(|format| format.to_mime_type());
+        // A search reports the stages of the attempt it kept, but the time of the whole search
+        output.timings.retain(|(stage, _)| *stage != "total");
+        output.timings.push(("total", start.elapsed()));
+        Ok(output)
+    }
+}
+
+/// Compress at a quality picked around `options.quality` by how complex the image is
+fn compress_adaptive(data: &[u8], options: &CompressionOptions) -> Result<CompressionOutput, String> {
+    let analysis = analyze_pixels(&decode_image(data, options)?, grayscale_tolerance(data));
+    let quality = analysis.adapt_quality(options.quality, options.adaptive_range);
+    let options = CompressionOptions { quality, adaptive_quality: false, ..options.clone() };
+    ImageType::compress_with_options(data, &options).map(|output| CompressionOutput {
+        achieved_quality: (!output.original_returned).then_some(quality),
+        complexity: Some(analysis.complexity()),
+        ..output
+    })
+}
+
+/// Compress at the given settings, without any target search
+fn compress_once(data: &[u8], options: &CompressionOptions) -> Result<CompressionOutput, String> {
+    let format = match (options.format, ImageType::detect_type(data)) {
+        (FormatChoice::Fixed(format), _) => format,
+        (_, Some(ImageType::PNG)) => OutputFormat::Png,
+        (_, Some(ImageType::JPEG)) => OutputFormat::Jpeg,
+        (_, None) => return Err("UnSupported image type".into()), // No compression if type is unknown
+    };
+    let output = encode_as(data, options, format)?;
+
+    // Handing back the input would change the format
+    if image::guess_format(data).ok() == Some(format.container()) {
+        Ok(guard_size(data, output, options))
+    } else {
+        Ok(output)
+    }
+}
+
+/// Encode any image the `image` crate can decode as `format`
+fn encode_as(data: &[u8], options: &CompressionOptions, format: OutputFormat) -> Result<CompressionOutput, String> {
+    match format {
+        // Apply PNG compression
+        OutputFormat::Png => do_png_compression_with_options(data, options),
+        OutputFormat::PngLossless => do_png_lossless_compression_with_options(data, options),
+        OutputFormat::Jpeg => match ImageType::detect_type(data) {
+            Some(ImageType::JPEG) => match split_gain_map(data) {
+                Some((primary, gain_map)) => compress_gain_map_jpeg(primary, gain_map, options),
+                None => compress_jpeg(data, options),
+            },
+            _ => do_jpeg_compression_with_options(data, options),
+        },
+        OutputFormat::WebP => do_webp_compression_with_options(data, options),
+        OutputFormat::Avif => do_avif_compression_with_options(data, options),
+    }
+}
+
+/// Apply `options.size_guard` to an output in the input's format
+fn guard_size(data: &[u8], mut output: CompressionOutput, options: &CompressionOptions) -> CompressionOutput {
+    // The original still carries what privacy mode scrubbed, so it is never handed back
+    let scrubbed = options.metadata == MetadataPolicy::Privacy && !output.removed_metadata.is_empty();
+    let Some(min_saving) = options.size_guard.filter(|_| !scrubbed) else {
+        return output;
+    };
+    // Nor is it when the pixels were changed on purpose: the caller asked for those pixels
+    let transforms = pixel_transforms(data, &output, options);
+    if transforms.is_empty() {
+        return apply_size_guard(data, output, min_saving);
+    }
+    if apply_size_guard(data, output.clone(), min_saving).original_returned {
+        output.warnings.push(format!(
+            "Output is not smaller than the input; kept anyway because the image was {}",
+            transforms.join(", ")
+        ));
+    }
+    output
+}
+
+/// What the encoder did to the pixels beyond compressing them, e.g. `rotated upright`
+fn pixel_transforms(data: &[u8], output: &CompressionOutput, options: &CompressionOptions) -> Vec<&'static str> {
+    // The coefficient path neither converts colours nor resizes
+    let pixel_path = output.path != Some(CompressionPath::JpegLossless);
+    let mut transforms = Vec::new();
+    if pixel_path
+        && options.convert_to_srgb
+        && (is_cmyk_jpeg(data) || read_metadata(data).icc.is_some_and(|icc| !is_srgb_profile(&icc)))
+    {
+        transforms.push("converted to sRGB");
+    }
+    if options.auto_orient && image_orientation(data) != 1 {
+        transforms.push("rotated upright");
+    }
+    if pixel_path && options.scale < 1.0 {
+        transforms.push("resized");
+    }
+    transforms
+}
+
+/// Default `max_dssim`: differences this small are hard to spot without flipping between images
+const AUTO_FORMAT_MAX_DSSIM: f64 = 0.01;
+
+/// Encode the source in every allowed format and keep the smallest output whose
+/// DSSIM against the source is within `target_dssim` (or `max_dssim` without one).
+/// JPEG is left out when the source has real transparency. When no candidate is
+/// good enough, the closest one is returned with a warning.
+fn compress_auto(data: &[u8], options: &CompressionOptions) -> Result<CompressionOutput, String> {
+    let reference = decode_image(data, options)?;
+    let needs_alpha = reference.color().has_alpha() && reference.to_rgba8().pixels().any(|p| p[3] < 255);
+    let threshold = options.target_dssim.unwrap_or(options.max_dssim);
+    // The output is already upright, converted and resized
+    let output_options = CompressionOptions { convert_to_srgb: false, scale: 1.0, ..options.clone() };
+
+    let mut warnings = Vec::new();
+    let mut candidates = Vec::new();
+    for &format in &options.allowed_formats {
+        if needs_alpha && !format.supports_alpha() {
+            continue;
+        }
+        if !format.container().reading_enabled() {
+            warnings.push(format!("{} skipped: this build cannot decode it to check its quality", format.name()));
+            continue;
+        }
+        let candidate_options =
+            CompressionOptions { format: FormatChoice::Fixed(format), size_guard: None, ..options.clone() };
+        let output = match ImageType::compress_with_options(data, &candidate_options) {
+            Ok(output) => output,
+            Err(e) => {
+                warnings.push(format!("{} skipped: {}", format.name(), e));
+                continue;
+            }
+        };
+        // A target_dssim search has measured it already
+        let dssim = match output.dssim {
+            Some(dssim) => dssim,
+            None => compare_decoded(&reference, &decode_image(&output.data, &output_options)?)?.dssim,
+        };
+        candidates.push(CompressionOutput { dssim: Some(dssim), ..output });
+    }
+
+    let dssim = |output: &CompressionOutput| output.dssim.unwrap_or(f64::INFINITY);
+    let smallest = candidates
+        .iter()
+        .filter(|output| dssim(output) <= threshold)
+        .min_by_key(|output| output.data.len())
+        .cloned();
+    let mut output = match smallest {
+        Some(output) => output,
+        None => {
+            let mut closest = candidates
+                .into_iter()
+                .min_by(|a, b| dssim(a).total_cmp(&dssim(b)))
+                .ok_or_else(|| match warnings.first() {
+                    Some(reason) => format!("No output format could be used; {}", reason),
+                    None => "No allowed output format can store this image".to_string(),
+                })?;
+            closest.warnings.push(format!(
+                "No format reached DSSIM {}; closest output has {:.6}",
+                threshold,
+                dssim(&closest)
+            ));
+            closest
+        }
+    };
+    output.warnings.extend(warnings);
+
+    let input_allowed = options
+        .allowed_formats
+        .iter()
+        .any(|format| image::guess_format(data).ok() == Some(format.container()));
+    Ok(if input_allowed { guard_size(data, output, options) } else { output })
+}
+
+/// Below this quality, shrinking the image looks better than compressing it harder
+const DOWNSCALE_QUALITY_FLOOR: u8 = 40;
+/// Downscaling stops before the shorter side drops below this many pixels
+const MIN_DOWNSCALED_SIDE: u32 = 16;
+
+/// Find the highest quality (and, with `allow_downscale`, the largest scale) whose
+/// output fits in `target` bytes. When nothing fits, the smallest output is
+/// returned with a warning.
+fn compress_to_size(data: &[u8], options: &CompressionOptions, target: usize) -> Result<CompressionOutput, String> {
+    let trial = |quality: u8, scale: f32| {
+        let options = CompressionOptions { quality, scale, target_size: None, ..options.clone() };
+        ImageType::compress_with_options(data, &options).map(|output| CompressionOutput {
+            achieved_quality: (!output.original_returned).then_some(quality),
+            scale: (scale < 1.0 && !output.original_returned).then_some(scale),
+            ..output
+        })
+    };
+    // Output size grows with quality, so binary search for the last quality that fits
+    let search = |floor: u8, scale: f32| -> Result<(Option<CompressionOutput>, CompressionOutput), String> {
+        let (mut low, mut high) = (floor as i32, 100);
+        let (mut best, mut smallest): (Option<CompressionOutput>, Option<CompressionOutput>) = (None, None);
+        while low <= high {
+            let quality = (low + high) / 2;
+            let output = trial(quality as u8, scale)?;
+            if output.data.len() <= target {
+                low = quality + 1;
+                best = Some(output);
+            } else {
+                high = quality - 1;
+                if smallest.as_ref().is_none_or(|s| output.data.len() < s.data.len()) {
+                    smallest = Some(output);
+                }
+            }
+        }
+        let smallest = match smallest {
+            Some(smallest) => smallest,
+            None => best.clone().ok_or("Target size search made no attempt")?,
+        };
+        Ok((best, smallest))
+    };
+
+    let floor = if options.allow_downscale { DOWNSCALE_QUALITY_FLOOR } else { 0 };
+    let (best, mut smallest) = search(floor, 1.0)?;
+    if let Some(best) = best {
+        return Ok(best);
+    }
+
+    if options.allow_downscale {
+        let probe = probe_image(data)?;
+        let shorter_side = probe.width.min(probe.height).max(1) as f32;
+        let min_scale = (MIN_DOWNSCALED_SIDE as f32 / shorter_side).min(1.0);
+        let mut scale = 1.0f32;
+        while scale > min_scale {
+            // Bytes scale roughly with pixel count
+            let ratio = (target as f32 / smallest.data.len() as f32).sqrt();
+            scale = (scale * ratio * 0.95).clamp(scale * 0.5, scale * 0.95).max(min_scale);
+            let (best, attempt) = search(floor, scale)?;
+            if let Some(best) = best {
+                return Ok(best);
+            }
+            smallest = attempt;
+        }
+        // Smallest allowed size and still too big: give up on the quality floor
+        let (best, attempt) = search(0, min_scale)?;
+        if let Some(best) = best {
+            return Ok(best);
+        }
+        smallest = attempt;
+    }
+
+    smallest.warnings.push(format!(
+        "Target size {} bytes not reached; smallest output is {} bytes",
+        target,
+        smallest.data.len()
+    ));
+    Ok(smallest)
+}
+
+/// Find the lowest quality whose output is within `target` DSSIM of the source.
+/// Quality numbers mean different things to each encoder; DSSIM is measured the
+/// same way for all of them. When no quality meets it, the closest output is
+/// returned with a warning.
+fn compress_to_dssim(data: &[u8], options: &CompressionOptions, target: f64) -> Result<CompressionOutput, String> {
+    let reference = decode_image(data, options)?;
+    // The output is already upright, converted and resized
+    let output_options = CompressionOptions { convert_to_srgb: false, scale: 1.0, ..options.clone() };
+    let trial = |quality: u8| -> Result<CompressionOutput, String> {
+        let options = CompressionOptions { quality, target_dssim: None, ..options.clone() };
+        let output = ImageType::compress_with_options(data, &options)?;
+        let decoded = match output.original_returned {
+            true => decode_image(&output.data, &options),
+            false => decode_image(&output.data, &output_options),
+        }?;
+        // Measured like `format=auto` and `compare_images`, so a target means the same everywhere
+        let dssim = compare_decoded(&reference, &decoded)?.dssim;
+        Ok(CompressionOutput {
+            achieved_quality: (!output.original_returned).then_some(quality),
+            dssim: Some(dssim),
+            ..output
+        })
+    };
+
+    // DSSIM falls as quality rises, so binary search for the first quality that meets it
+    let (mut low, mut high) = (0i32, 100i32);
+    let (mut best, mut closest): (Option<CompressionOutput>, Option<CompressionOutput>) = (None, None);
+    while low <= high {
+        let quality = (low + high) / 2;
+        let output = trial(quality as u8)?;
+        let dssim = output.dssim.unwrap_or(f64::INFINITY);
+        if dssim <= target {
+            high = quality - 1;
+            best = Some(output);
+        } else {
+            low = quality + 1;
+            if closest.as_ref().is_none_or(|c| dssim < c.dssim.unwrap_or(f64::INFINITY)) {
+                closest = Some(output);
             }
-            Some(ImageType::JPEG) => {
-                // Apply JPEG compression
-                do_jpeg_compression(data, quality)
+        }
+    }
+    match (best, closest) {
+        (Some(best), _) => Ok(best),
+        (None, Some(mut closest)) => {
+            closest.warnings.push(format!(
+                "Target DSSIM {} not reached; closest output has {:.6}",
+                target,
+                closest.dssim.unwrap_or(f64::INFINITY)
+            ));
+            Ok(closest)
+        }
+        (None, None) => Err("Target DSSIM search made no attempt".into()),
+    }
+}
+
+/// Recompress a single JPEG image, keeping its DCT coefficients when re-encoding can't help
+fn compress_jpeg(data: &[u8], options: &CompressionOptions) -> Result<CompressionOutput, String> {
+    let input_quality = estimate_jpeg_quality(data);
+    // Re-encoding at or above the quality the input was saved with
+    // is pure generation loss plus size growth
+    // ...unless the colours have to be converted, which needs pixels
+    let needs_conversion =
+        options.convert_to_srgb && jpeg_icc_profile(data).is_some_and(|icc| !is_srgb_profile(&icc));
+    let keep_coefficients = options.jpeg_mode == JpegMode::Lossless
+        || (!needs_conversion
+            && options.scale >= 1.0
+            && options.importance.is_none()
+            && options.respect_input_quality
+            && input_quality.is_some_and(|q| q <= encoder_annex_k_quality(options.quality)));
+
+    let orientation = if options.auto_orient { image_orientation(data) } else { 1 };
+
+    let output = match keep_coefficients {
+        // Explicit lossless mode trims partial edge MCUs if the rotation can't be done without
+        true if options.jpeg_mode == JpegMode::Lossless => {
+            // Coefficients can't be colour-converted: keep the profile so colours stay right
+            let lossless_options = CompressionOptions { convert_to_srgb: false, ..options.clone() };
+            let mut warnings = Vec::new();
+            if needs_conversion {
+                warnings.push("Lossless JPEG mode cannot convert to sRGB; ICC profile kept".to_string());
             }
+            let jpeg = match lossless_jpeg(data, orientation, false) {
+                Ok(jpeg) => jpeg,
+                Err(_) if orientation != 1 => {
+                    let jpeg = lossless_jpeg(data, orientation, true)?;
+                    let (before, after) = (probe_image(data)?, probe_image(&jpeg.data)?);
+                    warnings.push(format!(
+                        "Lossless rotation trimmed partial edge blocks: {}x{} rotated to {}x{}",
+                        before.width, before.height, after.width, after.height
+                    ));
+                    jpeg
+                }
+                Err(e) => return Err(e),
+            };
+            attach_metadata(data, jpeg, &lossless_options).map(|output| CompressionOutput { warnings, ..output })
+        }
+        true => lossless_jpeg(data, orientation, false)
+            .and_then(|jpeg| attach_metadata(data, jpeg, options))
+            .or_else(|e| {
+                // Not MCU-aligned: rotating the pixels is the only lossless-enough option left
+                if orientation == 1 { Err(e) } else { do_jpeg_compression_with_options(data, options) }
+            }),
+        // Apply JPEG compression
+        false => do_jpeg_compression_with_options(data, options),
+    };
+    output.map(|output| CompressionOutput { input_quality, ..output })
+}
+
+/// Rewrite the DCT coefficients, applying the EXIF rotation losslessly
+fn lossless_jpeg(data: &[u8], orientation: u8, trim: bool) -> Result<CompressionOutput, String> {
+    let mut stopwatch = Stopwatch::new();
+    let jpeg = do_jpeg_lossless_transform(data, true, orientation, trim)?;
+    stopwatch.lap("transform");
+    Ok(CompressionOutput {
+        path: Some(CompressionPath::JpegLossless),
+        timings: stopwatch.timings,
+        ..jpeg.into()
+    })
+}
+
+/// Recompress the SDR base image and the gain map of an Ultra HDR JPEG separately
+/// and reassemble them, so HDR displays still get the HDR rendition
+fn compress_gain_map_jpeg(
+    primary: &[u8],
+    gain_map: &[u8],
+    options: &CompressionOptions,
+) -> Result<CompressionOutput, String> {
+    let output = compress_jpeg(primary, options)?;
 
-            None => Err("UnSupported image type".into()), // No compression if type is unknown
+    // The gain map must keep its hdrgm metadata and follow the base image's rotation;
+    // borrowing the base image's EXIF orientation makes the JPEG paths rotate it too
+    let orientation = if options.auto_orient { image_orientation(primary) } else { 1 };
+    let gain_map_input = match orientation {
+        1 => gain_map.to_vec(),
+        _ => write_metadata(
+            &strip_jpeg_exif(gain_map),
+            &ImageMetadata { exif: Some(orientation_exif(orientation)), ..Default::default() },
+        )?,
+    };
+    // Gain map samples are data, not a picture: only the quality and the geometry carry
+    // over, never smoothing, chroma subsampling or colour conversion meant for the base image
+    let gain_map_options = CompressionOptions {
+        quality: options.quality,
+        jpeg_mode: options.jpeg_mode,
+        respect_input_quality: options.respect_input_quality,
+        auto_orient: options.auto_orient,
+        scale: options.scale,
+        format: FormatChoice::Fixed(OutputFormat::Jpeg),
+        metadata: MetadataPolicy::Keep,
+        keep_icc: true,
+        convert_to_srgb: false,
+        chroma_subsampling: ChromaSubsampling::Yuv444,
+        importance: None,
+        size_guard: None,
+        ..Default::default()
+    };
+    let encoded_gain_map = strip_jpeg_exif(&compress_jpeg(&gain_map_input, &gain_map_options)?.data);
+
+    Ok(CompressionOutput {
+        data: join_gain_map(&output.data, &encoded_gain_map, primary),
+        ..output
+    })
+}
+
+/// EXIF block holding only an Orientation tag
+fn orientation_exif(orientation: u8) -> Vec<u8> {
+    let field = ExifField {
+        tag: TAG_ORIENTATION,
+        field_type: 3,
+        count: 1,
+        value: ExifValue::Bytes((orientation as u16).to_be_bytes().to_vec()),
+    };
+    write_exif(&[field], false)
+}
+
+/// Remove the EXIF APP1 segments of a JPEG
+fn strip_jpeg_exif(data: &[u8]) -> Vec<u8> {
+    let mut out = data.to_vec();
+    for segment in jpeg_segments(data).iter().rev() {
+        if segment.marker == 0xE1 && segment.data.starts_with(b"Exif\0\0") {
+            out.drain(segment.offset..segment.offset + 4 + segment.data.len());
         }
     }
+    out
 }
 
 pub fn do_png_compression(data: &[u8], quality: u8) -> Result<Vec<u8>, String> {
-    // Load image data
-    let img =
-        image::load_from_memory(data).map_err(|e| format!("Failed to load PNG image: {}", e))?;
+    do_png_compression_with_options(data, &CompressionOptions::new(quality)).map(|output| output.data)
+}
 
-    // Convert to RGBA8 format for imagequant
+pub fn do_png_compression_with_options(
+    data: &[u8],
+    options: &CompressionOptions,
+) -> Result<CompressionOutput, String> {
+    let gamma = read_metadata(data).quantization_gamma();
+    decode_and_encode(data, options, "PNG", |img| quantize_png(img, gamma, options))
+}
+
+/// Lossless PNG keeping every pixel, at 16 bits per channel when the input has them
+pub fn do_png_lossless_compression_with_options(
+    data: &[u8],
+    options: &CompressionOptions,
+) -> Result<CompressionOutput, String> {
+    decode_and_encode(data, options, "lossless PNG", |img| encode_png_lossless(img, options))
+}
+
+fn encode_png_lossless(img: &image::DynamicImage, options: &CompressionOptions) -> Result<CompressionOutput, String> {
+    let mut stopwatch = Stopwatch::new();
+    let sixteen_bit = is_sixteen_bit(img);
+    let png = encode_truecolor_png(img, sixteen_bit, options.quality)?;
+    stopwatch.lap("encode");
+    Ok(CompressionOutput {
+        path: Some(if sixteen_bit { CompressionPath::Png16 } else { CompressionPath::PngLossless }),
+        timings: stopwatch.timings,
+        ..png.into()
+    })
+}
+
+/// Lossy WebP at `quality`, keeping the alpha channel when something is transparent
+pub fn do_webp_compression_with_options(
+    data: &[u8],
+    options: &CompressionOptions,
+) -> Result<CompressionOutput, String> {
+    decode_and_encode(data, options, "WebP", |img| encode_webp(img, options))
+}
+
+fn encode_webp(img: &image::DynamicImage, options: &CompressionOptions) -> Result<CompressionOutput, String> {
+    let mut stopwatch = Stopwatch::new();
+    let rgba_img = img.to_rgba8();
+    let (width, height) = (rgba_img.width(), rgba_img.height());
+    let webp_data = if rgba_img.pixels().any(|p| p[3] < 255) {
+        webp::Encoder::from_rgba(rgba_img.as_raw(), width, height).encode(options.quality as f32)
+    } else {
+        let rgb_img = img.to_rgb8();
+        webp::Encoder::from_rgb(rgb_img.as_raw(), width, height).encode(options.quality as f32)
+    };
+    stopwatch.lap("encode");
+    Ok(CompressionOutput {
+        path: Some(CompressionPath::WebP),
+        timings: stopwatch.timings,
+        ..webp_data.to_vec().into()
+    })
+}
+
+/// AVIF at `quality`, with `speed` passed on to the AV1 encoder. The output carries no metadata.
+pub fn do_avif_compression_with_options(
+    data: &[u8],
+    options: &CompressionOptions,
+) -> Result<CompressionOutput, String> {
+    let mut stopwatch = Stopwatch::new();
+    let img = decode_image(data, options).map_err(|e| format!("Failed to load image: {}", e))?;
+    stopwatch.lap("decode");
+    let mut encoded = encode_avif(&img, options)?;
+    stopwatch.timings.append(&mut encoded.timings);
+
+    let mut warnings = Vec::new();
+    if options.metadata != MetadataPolicy::Strip && !read_metadata(data).is_empty() {
+        warnings.push("AVIF output carries no metadata".to_string());
+    }
+    Ok(CompressionOutput { warnings, timings: stopwatch.timings, ..encoded })
+}
+
+fn encode_avif(img: &image::DynamicImage, options: &CompressionOptions) -> Result<CompressionOutput, String> {
+    let mut stopwatch = Stopwatch::new();
     let rgba_img = img.to_rgba8();
+    let img = if rgba_img.pixels().any(|p| p[3] < 255) {
+        image::DynamicImage::ImageRgba8(rgba_img)
+    } else {
+        image::DynamicImage::ImageRgb8(img.to_rgb8())
+    };
+    let mut avif_data = Vec::new();
+    let encoder =
+        image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut avif_data, options.speed, options.quality);
+    img.write_with_encoder(encoder)
+        .map_err(|e| format!("Failed to encode AVIF: {}", e))?;
+    stopwatch.lap("encode");
+    Ok(CompressionOutput {
+        path: Some(CompressionPath::Avif),
+        timings: stopwatch.timings,
+        ..avif_data.into()
+    })
+}
+
+/// Encode decoded pixels as `format`, without metadata. `gamma` is the source's
+/// gamma hint for imagequant (0.0 = sRGB), `gray_tolerance` the channel spread
+/// JPEG output still encodes as grayscale.
+pub(crate) fn encode_pixels(
+    img: &image::DynamicImage,
+    gamma: f64,
+    gray_tolerance: u8,
+    options: &CompressionOptions,
+    format: OutputFormat,
+) -> Result<CompressionOutput, String> {
+    match format {
+        OutputFormat::Png => quantize_png(img, gamma, options),
+        OutputFormat::PngLossless => encode_png_lossless(img, options),
+        OutputFormat::Jpeg => encode_jpeg_pixels(img, options, gray_tolerance),
+        OutputFormat::WebP => encode_webp(img, options),
+        OutputFormat::Avif => encode_avif(img, options),
+    }
+}
+
+/// Decode `data`, encode the pixels with `encode` and copy the metadata over, timing each stage
+fn decode_and_encode(
+    data: &[u8],
+    options: &CompressionOptions,
+    format_name: &str,
+    encode: impl FnOnce(&image::DynamicImage) -> Result<CompressionOutput, String>,
+) -> Result<CompressionOutput, String> {
+    let mut stopwatch = Stopwatch::new();
+    let img = decode_image(data, options).map_err(|e| format!("Failed to load {} image: {}", format_name, e))?;
+    stopwatch.lap("decode");
+    let mut encoded = encode(&img)?;
+    stopwatch.timings.append(&mut encoded.timings);
+    attach_metadata(data, CompressionOutput { timings: stopwatch.timings, ..encoded }, options)
+}
+
+fn is_sixteen_bit(img: &image::DynamicImage) -> bool {
+    matches!(
+        img.color(),
+        image::ColorType::L16 | image::ColorType::La16 | image::ColorType::Rgb16 | image::ColorType::Rgba16
+    )
+}
+
+/// Copy the metadata `options.metadata` allows from the input onto a freshly encoded image
+fn attach_metadata(
+    input: &[u8],
+    encoded: CompressionOutput,
+    options: &CompressionOptions,
+) -> Result<CompressionOutput, String> {
+    let mut stopwatch = Stopwatch::new();
+    let source = read_metadata(input);
+    let (mut metadata, removed_metadata) = source.filtered_with_report(options.metadata);
+    if options.convert_to_srgb && source.icc.is_some() {
+        // The pixels are sRGB now
+        metadata.icc = None;
+        metadata.srgb_intent = None;
+        metadata.gamma = None;
+        metadata.chromaticities = None;
+    } else if options.keep_icc {
+        let hints = source.color_hints();
+        metadata.icc = hints.icc;
+        metadata.srgb_intent = hints.srgb_intent;
+        metadata.gamma = hints.gamma;
+        metadata.chromaticities = hints.chromaticities;
+    }
+    // The pixels were already turned upright
+    if options.auto_orient {
+        metadata.reset_orientation();
+    }
+    let data = write_metadata(&encoded.data, &metadata)?;
+    stopwatch.lap("metadata");
+    let mut timings = encoded.timings;
+    timings.extend(stopwatch.timings);
+    Ok(CompressionOutput { data, removed_metadata, timings, ..encoded })
+}
+
+/// Quantize to a palette PNG (or the grayscale/16-bit special cases), reporting
+/// the palette and imagequant's quality figures
+fn quantize_png(img: &image::DynamicImage, gamma: f64, options: &CompressionOptions) -> Result<CompressionOutput, String> {
+    let quality = options.quality;
+    let mut stopwatch = Stopwatch::new();
+
+    let sixteen_bit = is_sixteen_bit(img);
+    if sixteen_bit && options.sixteen_bit == SixteenBitPolicy::Lossless {
+        let png = encode_truecolor_png(img, true, quality)?;
+        stopwatch.lap("encode");
+        return Ok(CompressionOutput {
+            path: Some(CompressionPath::Png16),
+            timings: stopwatch.timings,
+            ..png.into()
+        });
+    }
+
+    // Convert to RGBA8 format for imagequant
+    let rgba_img = if sixteen_bit && options.sixteen_bit == SixteenBitPolicy::Dither {
+        dither_to_rgba8(&img.to_rgba16())
+    } else {
+        img.to_rgba8()
+    };
     let width = rgba_img.width() as usize;
     let height = rgba_img.height() as usize;
     let image_data = rgba_img.as_raw();
 
     // Use imagequant for color quantization
     let mut liq = imagequant::new();
-    liq.set_quality(0, quality)
+    liq.set_speed(options.speed as i32)
+        .map_err(|e| format!("Failed to set PNG speed: {:?}", e))?;
+    liq.set_max_colors(options.max_colors)
+        .map_err(|e| format!("Failed to set PNG max colors: {:?}", e))?;
+    liq.set_min_posterization(options.posterize)
+        .map_err(|e| format!("Failed to set PNG posterization: {:?}", e))?;
+    liq.set_quality(options.min_quality.min(quality), quality)
         .map_err(|e| format!("Failed to set PNG quality: {:?}", e))?;
 
     // Convert Vec<u8> to the format imagequant expects
@@ -68,22 +1356,57 @@ pub fn do_png_compression(data: &[u8], quality: u8) -> Result<Vec<u8>, String> {
 
     // Create image for quantization
     let mut img_quantize = liq
-        .new_image(&rgba_pixels[..], width, height, 0.0)
+        .new_image(&rgba_pixels[..], width, height, gamma)
         .map_err(|e| format!("Failed to create quantized image: {:?}", e))?;
+    if let Some(source) = &options.importance {
+        let map = importance_map(img, source)?;
+        img_quantize
+            .set_importance_map(map.data)
+            .map_err(|e| format!("Failed to set importance map: {:?}", e))?;
+    }
 
     // Quantize the image
     let mut res = liq
         .quantize(&mut img_quantize)
-        .map_err(|e| format!("Failed to quantize PNG: {:?}", e))?;
+        .map_err(|e| match e {
+            imagequant::Error::QualityTooLow => {
+                format!("PNG palette quality is below min_quality {}", options.min_quality)
+            }
+            e => format!("Failed to quantize PNG: {:?}", e),
+        })?;
+
+    stopwatch.lap("quantize");
 
     // Set dithering level (0.0 - 1.0)
-    res.set_dithering_level(1.0)
+    res.set_dithering_level(options.dithering)
         .map_err(|e| format!("Failed to set dithering: {:?}", e))?;
 
     // Get quantized data - this time we actually use it!
     let (palette, pixels) = res
         .remapped(&mut img_quantize)
         .map_err(|e| format!("Failed to remap PNG: {:?}", e))?;
+    stopwatch.lap("remap");
+    let report = CompressionOutput {
+        palette_size: Some(palette.len()),
+        quantization_quality: res.quantization_quality(),
+        quantization_error: res.quantization_error(),
+        ..Default::default()
+    };
+
+    // Scanned documents and black-and-white photos don't need a palette:
+    // write them as grayscale at the smallest bit depth that holds every level
+    if is_opaque_grayscale_rgba(image_data) {
+        let levels: Vec<u8> = palette.iter().map(|color| gray_level(&[color.r, color.g, color.b])).collect();
+        let gray: Vec<u8> = pixels.iter().map(|&index| levels[index as usize]).collect();
+        let data = encode_grayscale_png(&gray, width, height, quality)?;
+        stopwatch.lap("encode");
+        return Ok(CompressionOutput {
+            data,
+            path: Some(CompressionPath::PngGrayscale),
+            timings: stopwatch.timings,
+            ..report
+        });
+    }
 
     // Create PNG with indexed colors using the quantized palette
     let mut png_data = Vec::new();
@@ -92,15 +1415,7 @@ pub fn do_png_compression(data: &[u8], quality: u8) -> Result<Vec<u8>, String> {
         let mut encoder = png::Encoder::new(Cursor::new(&mut png_data), width as u32, height as u32);
         encoder.set_color(png::ColorType::Indexed);
         encoder.set_depth(png::BitDepth::Eight);
-        
-        // Set compression level based on quality (inverted: lower quality = higher compression)
-        let compression_level = match quality {
-            0..=25 => png::Compression::Best,
-            26..=50 => png::Compression::Fast,
-            51..=75 => png::Compression::Default,
-            _ => png::Compression::Fast,
-        };
-        encoder.set_compression(compression_level);
+        encoder.set_compression(png_compression_level(quality));
         
         // Convert palette to the format PNG encoder expects
         let png_palette: Vec<u8> = palette.iter()
@@ -116,29 +1431,145 @@ pub fn do_png_compression(data: &[u8], quality: u8) -> Result<Vec<u8>, String> {
         writer.write_image_data(&pixels)
             .map_err(|e| format!("Failed to write PNG data: {}", e))?;
     }
+    stopwatch.lap("encode");
 
-    Ok(png_data)
+    Ok(CompressionOutput {
+        data: png_data,
+        path: Some(CompressionPath::PngPalette),
+        timings: stopwatch.timings,
+        ..report
+    })
 }
 
 pub fn do_jpeg_compression(data: &[u8], quality: u8) -> Result<Vec<u8>, String> {
-    // Load image data
-    let img =
-        image::load_from_memory(data).map_err(|e| format!("Failed to load JPEG image: {}", e))?;
+    do_jpeg_compression_with_options(data, &CompressionOptions::new(quality)).map(|output| output.data)
+}
+
+pub fn do_jpeg_compression_with_options(
+    data: &[u8],
+    options: &CompressionOptions,
+) -> Result<CompressionOutput, String> {
+    decode_and_encode(data, options, "JPEG", |img| encode_jpeg_pixels(img, options, grayscale_tolerance(data)))
+}
+
+/// Flatten, smooth by importance and encode decoded pixels with mozjpeg, as
+/// grayscale when no pixel's channels differ by more than `gray_tolerance`
+fn encode_jpeg_pixels(
+    img: &image::DynamicImage,
+    options: &CompressionOptions,
+    gray_tolerance: u8,
+) -> Result<CompressionOutput, String> {
+    let mut stopwatch = Stopwatch::new();
+    let mut warnings = Vec::new();
+
+    // JPEG has no alpha channel: composite transparent pixels onto the
+    // background instead of letting to_rgb8() expose whatever colour is underneath
+    let rgb_img = if img.color().has_alpha() {
+        let rgba_img = img.to_rgba8();
+        if rgba_img.pixels().any(|p| p[3] < 255) {
+            match options.alpha_policy {
+                AlphaPolicy::Flatten => {}
+                AlphaPolicy::Warn => warnings.push(format!(
+                    "Input has transparency; flattened onto background #{:02x}{:02x}{:02x}",
+                    options.background[0], options.background[1], options.background[2]
+                )),
+                AlphaPolicy::Error => {
+                    return Err("Input has transparency, which JPEG cannot store".into());
+                }
+            }
+        }
+        flatten_alpha(&rgba_img, options.background)
+    } else {
+        img.to_rgb8()
+    };
+
+    // Spend the bits where they matter: smooth what the importance map calls background
+    let rgb_img = match &options.importance {
+        Some(source) => smooth_unimportant(&rgb_img, &importance_map(img, source)?, options.background_blur),
+        None => rgb_img,
+    };
 
-    // Convert to RGB format for JPEG
-    let rgb_img = img.to_rgb8();
     let width = rgb_img.width() as usize;
     let height = rgb_img.height() as usize;
+    let image_data = rgb_img.as_raw();
+
+    // Encode a single luma channel when the content carries no colour
+    let encoded = if is_grayscale_rgb(image_data, gray_tolerance) {
+        let luma: Vec<u8> = image_data.chunks_exact(3).map(gray_level).collect();
+        encode_jpeg(&luma, width, height, mozjpeg::ColorSpace::JCS_GRAYSCALE, options)?
+    } else {
+        encode_jpeg(image_data, width, height, mozjpeg::ColorSpace::JCS_RGB, options)?
+    };
+    stopwatch.lap("encode");
+    Ok(CompressionOutput {
+        path: Some(CompressionPath::JpegReencode),
+        warnings,
+        timings: stopwatch.timings,
+        ..encoded.into()
+    })
+}
+
+/// Decode to pixels (CMYK/YCCK JPEGs through the colour-correct CMYK decoder),
+/// turned upright when `auto_orient` is set
+/// (the re-encoded file carries no EXIF to do it for the viewer)
+pub(crate) fn decode_image(data: &[u8], options: &CompressionOptions) -> Result<image::DynamicImage, String> {
+    let mut img = if is_cmyk_jpeg(data) {
+        decode_cmyk_jpeg(data).map(image::DynamicImage::ImageRgb8)?
+    } else {
+        image::load_from_memory(data).map_err(|e| e.to_string())?
+    };
+    // CMYK JPEGs are already converted to sRGB by their decoder
+    if options.convert_to_srgb
+        && !is_cmyk_jpeg(data)
+        && let Some(icc) = read_metadata(data).icc
+    {
+        img = convert_to_srgb(&img, &icc)?;
+    }
+    if options.auto_orient
+        && let Some(orientation) = image::metadata::Orientation::from_exif(image_orientation(data))
+    {
+        img.apply_orientation(orientation);
+    }
+    if options.scale < 1.0 {
+        let width = ((img.width() as f32 * options.scale).round() as u32).max(1);
+        let height = ((img.height() as f32 * options.scale).round() as u32).max(1);
+        img = img.resize_exact(width, height, image::imageops::FilterType::Lanczos3);
+    }
+    Ok(img)
+}
 
+/// Composite RGBA pixels onto an opaque background colour
+pub(crate) fn flatten_alpha(rgba_img: &image::RgbaImage, background: [u8; 3]) -> image::RgbImage {
+    let mut rgb_img = image::RgbImage::new(rgba_img.width(), rgba_img.height());
+    for (src, dst) in rgba_img.pixels().zip(rgb_img.pixels_mut()) {
+        let alpha = src[3] as u32;
+        for c in 0..3 {
+            dst[c] = ((src[c] as u32 * alpha + background[c] as u32 * (255 - alpha) + 127) / 255) as u8;
+        }
+    }
+    rgb_img
+}
+
+/// Encode interleaved pixels in `color_space` with mozjpeg
+fn encode_jpeg(
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+    color_space: mozjpeg::ColorSpace,
+    options: &CompressionOptions,
+) -> Result<Vec<u8>, String> {
     // Create output buffer
     let mut jpeg_data = Vec::new();
 
     // Create mozjpeg compressor
-    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    let mut comp = mozjpeg::Compress::new(color_space);
 
     // Set compression parameters
     comp.set_size(width, height);
-    comp.set_quality(quality as f32);
+    comp.set_quality(options.quality as f32);
+    if options.chroma_subsampling == ChromaSubsampling::Yuv444 && color_space == mozjpeg::ColorSpace::JCS_RGB {
+        comp.set_chroma_sampling_pixel_sizes((1, 1), (1, 1));
+    }
 
     // Start compression with output buffer
     let mut comp_started = comp
@@ -146,13 +1577,12 @@ pub fn do_jpeg_compression(data: &[u8], quality: u8) -> Result<Vec<u8>, String>
         .map_err(|e| format!("Failed to start JPEG compression: {}", e))?;
 
     // Write image data row by row
-    let row_stride = width * 3; // RGB = 3 bytes per pixel
-    let image_data = rgb_img.as_raw();
+    let row_stride = width * color_space_components(color_space);
 
     for y in 0..height {
         let row_start = y * row_stride;
         let row_end = row_start + row_stride;
-        let row = &image_data[row_start..row_end];
+        let row = &pixels[row_start..row_end];
         comp_started
             .write_scanlines(row)
             .map_err(|e| format!("Failed to write JPEG scanline: {}", e))?;
@@ -166,6 +1596,190 @@ pub fn do_jpeg_compression(data: &[u8], quality: u8) -> Result<Vec<u8>, String>
     Ok(jpeg_data)
 }
 
+fn color_space_components(color_space: mozjpeg::ColorSpace) -> usize {
+    match color_space {
+        mozjpeg::ColorSpace::JCS_GRAYSCALE => 1,
+        mozjpeg::ColorSpace::JCS_CMYK | mozjpeg::ColorSpace::JCS_YCCK => 4,
+        _ => 3,
+    }
+}
+
+/// Set compression level based on quality (inverted: lower quality = higher compression)
+fn png_compression_level(quality: u8) -> png::Compression {
+    match quality {
+        0..=25 => png::Compression::Best,
+        26..=50 => png::Compression::Fast,
+        51..=75 => png::Compression::Default,
+        _ => png::Compression::Fast,
+    }
+}
+
+/// Largest spread between the channels of a pixel still taken as gray in pixels
+/// decoded from a JPEG: grayscale photos stored as YCbCr come back with a level or
+/// two of chroma rounding noise. Pixels from anything else have to be exactly gray.
+pub(crate) const JPEG_GRAYSCALE_TOLERANCE: u8 = 2;
+
+/// The channel spread [`is_gray_pixel`] allows for pixels decoded from `data`
+pub(crate) fn grayscale_tolerance(data: &[u8]) -> u8 {
+    match ImageType::detect_type(data) {
+        Some(ImageType::JPEG) => JPEG_GRAYSCALE_TOLERANCE,
+        _ => 0,
+    }
+}
+
+pub(crate) fn is_gray_pixel(p: &[u8], tolerance: u8) -> bool {
+    let (max, min) = (p[0].max(p[1]).max(p[2]), p[0].min(p[1]).min(p[2]));
+    max - min <= tolerance
+}
+
+/// BT.601 luma of an RGB pixel
+fn gray_level(p: &[u8]) -> u8 {
+    ((p[0] as u32 * 77 + p[1] as u32 * 150 + p[2] as u32 * 29 + 128) >> 8) as u8
+}
+
+/// True when every pixel is gray within `tolerance`
+pub(crate) fn is_grayscale_rgb(pixels: &[u8], tolerance: u8) -> bool {
+    pixels.chunks_exact(3).all(|p| is_gray_pixel(p, tolerance))
+}
+
+/// True when every pixel is exactly gray and fully opaque
+pub(crate) fn is_opaque_grayscale_rgba(pixels: &[u8]) -> bool {
+    pixels.chunks_exact(4).all(|p| is_gray_pixel(p, 0) && p[3] == 255)
+}
+
+/// Smallest PNG grayscale bit depth (1, 2, 4 or 8) that represents every level exactly
+pub(crate) fn grayscale_bit_depth(levels: &[u8]) -> u8 {
+    [1u8, 2, 4]
+        .into_iter()
+        .find(|&depth| {
+            let step = 255 / ((1u16 << depth) - 1) as u8;
+            levels.iter().all(|&v| v % step == 0)
+        })
+        .unwrap_or(8)
+}
+
+/// Floyd-Steinberg reduction of 16-bit RGBA to 8 bits per channel
+pub(crate) fn dither_to_rgba8(img: &image::ImageBuffer<image::Rgba<u16>, Vec<u16>>) -> image::RgbaImage {
+    let width = img.width() as usize;
+    let samples = img.as_raw();
+    let mut out = image::RgbaImage::new(img.width(), img.height());
+
+    // Quantization error in 16-bit units for the current and the next row
+    let mut current = vec![0i32; (width + 2) * 4];
+    let mut next = vec![0i32; (width + 2) * 4];
+    for (row, out_row) in samples.chunks_exact(width * 4).zip(out.chunks_exact_mut(width * 4)) {
+        for x in 0..width {
+            for c in 0..4 {
+                let i = x * 4 + c;
+                let e = (x + 1) * 4 + c;
+                let value = (row[i] as i32 + current[e] / 16).clamp(0, 65535);
+                let reduced = (value + 128) / 257;
+                out_row[i] = reduced as u8;
+
+                let error = value - reduced * 257;
+                current[e + 4] += error * 7;
+                next[e - 4] += error * 3;
+                next[e] += error * 5;
+                next[e + 4] += error;
+            }
+        }
+        std::mem::swap(&mut current, &mut next);
+        next.fill(0);
+    }
+    out
+}
+
+/// Lossless truecolour PNG at 8 or 16 bits per channel: grayscale when R = G = B,
+/// alpha only when something is transparent
+fn encode_truecolor_png(img: &image::DynamicImage, sixteen_bit: bool, quality: u8) -> Result<Vec<u8>, String> {
+    let rgba = img.to_rgba16();
+    let samples = rgba.as_raw();
+    let opaque = samples.chunks_exact(4).all(|p| p[3] == u16::MAX);
+    let gray = samples.chunks_exact(4).all(|p| p[0] == p[1] && p[1] == p[2]);
+
+    let (color_type, channels): (png::ColorType, &[usize]) = match (gray, opaque) {
+        (true, true) => (png::ColorType::Grayscale, &[0]),
+        (true, false) => (png::ColorType::GrayscaleAlpha, &[0, 3]),
+        (false, true) => (png::ColorType::Rgb, &[0, 1, 2]),
+        (false, false) => (png::ColorType::Rgba, &[0, 1, 2, 3]),
+    };
+    // 8-bit samples widen to v * 257, so the high byte is the original value
+    let bytes: Vec<u8> = match sixteen_bit {
+        true => samples
+            .chunks_exact(4)
+            .flat_map(|p| channels.iter().flat_map(move |&c| p[c].to_be_bytes()))
+            .collect(),
+        false => samples
+            .chunks_exact(4)
+            .flat_map(|p| channels.iter().map(move |&c| (p[c] >> 8) as u8))
+            .collect(),
+    };
+
+    let mut png_data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(Cursor::new(&mut png_data), rgba.width(), rgba.height());
+        encoder.set_color(color_type);
+        encoder.set_depth(if sixteen_bit { png::BitDepth::Sixteen } else { png::BitDepth::Eight });
+        encoder.set_compression(png_compression_level(quality));
+        encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+
+        let mut writer = encoder.write_header()
+            .map_err(|e| format!("Failed to write PNG header: {}", e))?;
+        writer.write_image_data(&bytes)
+            .map_err(|e| format!("Failed to write PNG data: {}", e))?;
+    }
+
+    Ok(png_data)
+}
+
+/// Write 8-bit gray samples as a grayscale PNG, packing them to a lower bit depth when possible
+fn encode_grayscale_png(gray: &[u8], width: usize, height: usize, quality: u8) -> Result<Vec<u8>, String> {
+    let mut used = [false; 256];
+    for &v in gray {
+        used[v as usize] = true;
+    }
+    let levels: Vec<u8> = (0..=255u8).filter(|&v| used[v as usize]).collect();
+    let depth = grayscale_bit_depth(&levels);
+
+    let packed = if depth == 8 {
+        gray.to_vec()
+    } else {
+        let step = 255 / ((1u16 << depth) - 1) as u8;
+        let per_byte = 8 / depth as usize;
+        let row_bytes = width.div_ceil(per_byte);
+        let mut packed = vec![0u8; row_bytes * height];
+        for (row, out) in gray.chunks_exact(width).zip(packed.chunks_exact_mut(row_bytes)) {
+            for (x, &v) in row.iter().enumerate() {
+                let shift = 8 - depth as usize * (x % per_byte + 1);
+                out[x / per_byte] |= (v / step) << shift;
+            }
+        }
+        packed
+    };
+
+    let bit_depth = match depth {
+        1 => png::BitDepth::One,
+        2 => png::BitDepth::Two,
+        4 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight,
+    };
+
+    let mut png_data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(Cursor::new(&mut png_data), width as u32, height as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(bit_depth);
+        encoder.set_compression(png_compression_level(quality));
+
+        let mut writer = encoder.write_header()
+            .map_err(|e| format!("Failed to write PNG header: {}", e))?;
+        writer.write_image_data(&packed)
+            .map_err(|e| format!("Failed to write PNG data: {}", e))?;
+    }
+
+    Ok(png_data)
+}
+
 //
 // pub fn do_webp_compression(data: &[u8], quality: u8) -> Result<Vec<u8>, String> {
 //     // Load image data
@@ -192,3 +1806,776 @@ pub fn do_jpeg_compression(data: &[u8], quality: u8) -> Result<Vec<u8>, String>
 //
 //     Ok(webp_data.to_vec())
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{encode, gradient};
+    use image::{DynamicImage, GenericImageView, ImageFormat, Rgb, RgbImage};
+
+    #[test]
+    fn test_grayscale_bit_depth() {
+        assert_eq!(grayscale_bit_depth(&[0, 255]), 1);
+        assert_eq!(grayscale_bit_depth(&[0, 85, 170, 255]), 2);
+        assert_eq!(grayscale_bit_depth(&[17, 34, 238]), 4);
+        assert_eq!(grayscale_bit_depth(&[0, 1, 255]), 8);
+    }
+
+    #[test]
+    fn test_grayscale_png_output() {
+        let data = encode(&gradient(64, 48, false), ImageFormat::Png);
+        let compressed = do_png_compression(&data, 80).unwrap();
+        let decoded = image::load_from_memory(&compressed).unwrap();
+        assert!(matches!(decoded.color(), image::ColorType::L8));
+        assert_eq!((decoded.width(), decoded.height()), (64, 48));
+
+        let colored = encode(&gradient(64, 48, true), ImageFormat::Png);
+        let compressed = do_png_compression(&colored, 80).unwrap();
+        let decoded = image::load_from_memory(&compressed).unwrap();
+        assert!(!matches!(decoded.color(), image::ColorType::L8));
+    }
+
+    #[test]
+    fn test_black_and_white_png_uses_one_bit() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(33, 10, |x, y| {
+            if (x + y) % 3 == 0 { Rgb([0, 0, 0]) } else { Rgb([255, 255, 255]) }
+        }));
+        let compressed = do_png_compression(&encode(&img, ImageFormat::Png), 80).unwrap();
+        let decoder = png::Decoder::new(Cursor::new(&compressed));
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().color_type, png::ColorType::Grayscale);
+        assert_eq!(reader.info().bit_depth, png::BitDepth::One);
+
+        let decoded = image::load_from_memory(&compressed).unwrap().to_luma8();
+        assert_eq!(decoded.get_pixel(0, 0)[0], 0);
+        assert_eq!(decoded.get_pixel(1, 0)[0], 255);
+        assert_eq!(decoded.get_pixel(32, 9)[0], 255);
+    }
+
+    #[test]
+    fn test_jpeg_flattens_alpha_onto_background() {
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(32, 32, |x, _| {
+            if x < 16 { image::Rgba([0, 0, 0, 0]) } else { image::Rgba([200, 30, 30, 255]) }
+        }));
+        let data = encode(&img, ImageFormat::Png);
+
+        let output = do_jpeg_compression_with_options(&data, &CompressionOptions::new(90)).unwrap();
+        assert!(output.warnings.is_empty());
+        let decoded = image::load_from_memory(&output.data).unwrap().to_rgb8();
+        assert!(decoded.get_pixel(2, 16).0.iter().all(|&c| c > 240));
+
+        let options = CompressionOptions {
+            background: [0, 0, 255],
+            alpha_policy: AlphaPolicy::Warn,
+            ..CompressionOptions::new(90)
+        };
+        let output = do_jpeg_compression_with_options(&data, &options).unwrap();
+        assert_eq!(output.warnings.len(), 1);
+        let pixel = image::load_from_memory(&output.data).unwrap().to_rgb8().get_pixel(2, 16).0;
+        assert!(pixel[2] > 200 && pixel[0] < 40);
+
+        let options = CompressionOptions { alpha_policy: AlphaPolicy::Error, ..Default::default() };
+        assert!(do_jpeg_compression_with_options(&data, &options).is_err());
+    }
+
+    #[test]
+    fn test_flatten_alpha_blends() {
+        let rgba = image::RgbaImage::from_pixel(1, 1, image::Rgba([0, 0, 0, 128]));
+        assert_eq!(flatten_alpha(&rgba, [255, 255, 255]).get_pixel(0, 0).0, [127, 127, 127]);
+    }
+
+    #[test]
+    fn test_size_guard_returns_original() {
+        // A tiny, already optimal PNG only grows when re-encoded
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([10, 200, 30])));
+        let data = encode(&img, ImageFormat::Png);
+        let output = ImageType::compress_with_options(&data, &CompressionOptions::new(80)).unwrap();
+        assert!(output.data.len() <= data.len());
+
+        // Requiring a 99% saving forces the original back
+        let options = CompressionOptions { size_guard: Some(0.99), ..CompressionOptions::new(80) };
+        let output = ImageType::compress_with_options(&data, &options).unwrap();
+        assert!(output.original_returned);
+        assert_eq!(output.data, data);
+        assert!(output.summary().contains("original_returned=true"));
+
+        let options = CompressionOptions { size_guard: None, ..CompressionOptions::new(80) };
+        let output = ImageType::compress_with_options(&data, &options).unwrap();
+        assert!(!output.original_returned);
+
+        // The quality-only entry point keeps returning the new encoding
+        assert_eq!(ImageType::compress(&data, 80).unwrap(), output.data);
+    }
+
+    #[test]
+    fn test_skip_reencoding_low_quality_jpeg() {
+        let png = encode(&gradient(64, 48, true), ImageFormat::Png);
+        let jpeg_q60 = do_jpeg_compression(&png, 60).unwrap();
+
+        // Only the lossless path runs, so the pixels stay exactly the same
+        let output = ImageType::compress_with_options(&jpeg_q60, &CompressionOptions::new(80)).unwrap();
+        assert_eq!(output.input_quality, Some(encoder_annex_k_quality(60)));
+        assert!(output.data.len() <= jpeg_q60.len());
+        assert_eq!(
+            image::load_from_memory(&output.data).unwrap().to_rgb8(),
+            image::load_from_memory(&jpeg_q60).unwrap().to_rgb8()
+        );
+
+        let options = CompressionOptions {
+            respect_input_quality: false,
+            size_guard: None,
+            ..CompressionOptions::new(80)
+        };
+        let output = ImageType::compress_with_options(&jpeg_q60, &options).unwrap();
+        assert!(!output.original_returned);
+        assert_ne!(output.data, jpeg_q60);
+    }
+
+    #[test]
+    fn test_auto_orient() {
+        use crate::exif::tests::{orientation_tiff, with_exif};
+
+        let jpeg = encode(&gradient(48, 32, true), ImageFormat::Jpeg);
+        let rotated = with_exif(&jpeg, &orientation_tiff(6, false));
+
+        // Re-encoded from pixels: rotated upright, the EXIF tag is gone
+        let options = CompressionOptions { respect_input_quality: false, size_guard: None, ..CompressionOptions::new(70) };
+        let output = ImageType::compress_with_options(&rotated, &options).unwrap();
+        assert_eq!(image::load_from_memory(&output.data).unwrap().dimensions(), (32, 48));
+        assert_eq!(crate::exif::image_orientation(&output.data), 1);
+
+        let options = CompressionOptions { auto_orient: false, ..options };
+        let output = ImageType::compress_with_options(&rotated, &options).unwrap();
+        assert_eq!(image::load_from_memory(&output.data).unwrap().dimensions(), (48, 32));
+
+        // Coefficient path: lossless rotation, EXIF kept but reset to upright
+        let options = CompressionOptions {
+            jpeg_mode: JpegMode::Lossless,
+            metadata: MetadataPolicy::Keep,
+            size_guard: None,
+            ..Default::default()
+        };
+        let output = ImageType::compress_with_options(&rotated, &options).unwrap();
+        assert_eq!(image::load_from_memory(&output.data).unwrap().dimensions(), (32, 48));
+        assert_eq!(crate::exif::image_orientation(&output.data), 1);
+        assert!(crate::exif::jpeg_exif(&output.data).is_some());
+        assert!(output.warnings.is_empty());
+
+        // 90x60 leaves partial MCUs: trimmed, and the output says so
+        let odd = with_exif(&encode(&gradient(90, 60, true), ImageFormat::Jpeg), &orientation_tiff(6, false));
+        let output = ImageType::compress_with_options(&odd, &options).unwrap();
+        assert_eq!(image::load_from_memory(&output.data).unwrap().dimensions(), (56, 90));
+        assert!(output.warnings.iter().any(|w| w.contains("trimmed")), "{:?}", output.warnings);
+    }
+
+    #[test]
+    fn test_compression_report() {
+        let png = encode(&gradient(64, 48, true), ImageFormat::Png);
+        let options = CompressionOptions { max_colors: 32, size_guard: None, ..CompressionOptions::new(60) };
+        let output = ImageType::compress_with_options(&png, &options).unwrap();
+        assert_eq!(output.input_size, png.len());
+        assert_eq!(output.path, Some(CompressionPath::PngPalette));
+        assert!(output.palette_size.is_some_and(|n| n <= 32));
+        assert!(output.quantization_quality.is_some() && output.quantization_error.is_some());
+        let stages: Vec<&str> = output.timings.iter().map(|(stage, _)| *stage).collect();
+        assert_eq!(stages, ["decode", "quantize", "remap", "encode", "metadata", "total"]);
+        let summary = output.summary();
+        assert!(summary.contains("path=png_palette") && summary.contains("time.total_ms="));
+        assert!((output.ratio() - output.data.len() as f64 / png.len() as f64).abs() < 1e-12);
+
+        let jpeg = encode(&gradient(64, 48, true), ImageFormat::Jpeg);
+        let output = ImageType::compress_with_options(&jpeg, &CompressionOptions::new(50)).unwrap();
+        assert_eq!(output.path, Some(CompressionPath::JpegReencode));
+        assert_eq!(output.palette_size, None);
+        let output = ImageType::compress_with_options(&jpeg, &CompressionOptions::new(95)).unwrap();
+        assert_eq!(output.path, Some(CompressionPath::JpegLossless));
+        assert_eq!(output.timings.first().map(|(stage, _)| *stage), Some("transform"));
+    }
+
+    #[test]
+    fn test_target_size() {
+        // Detailed content, so the JPEG headers don't dominate the budget
+        let noisy = RgbImage::from_fn(256, 192, |x, y| {
+            let v = (x.wrapping_mul(2654435761) ^ y.wrapping_mul(40503)) >> 8;
+            Rgb([v as u8, (v >> 8) as u8, (x + y) as u8])
+        });
+        let jpeg = encode(&DynamicImage::ImageRgb8(noisy), ImageFormat::Jpeg);
+        let size_at = |quality| {
+            let options = CompressionOptions { respect_input_quality: false, ..CompressionOptions::new(quality) };
+            ImageType::compress_with_options(&jpeg, &options).unwrap().data.len()
+        };
+
+        let target = size_at(50);
+        let options = CompressionOptions { target_size: Some(target), respect_input_quality: false, ..Default::default() };
+        let output = ImageType::compress_with_options(&jpeg, &options).unwrap();
+        assert!(output.data.len() <= target);
+        assert!(output.achieved_quality.unwrap() >= 50);
+        assert!(output.scale.is_none() && output.warnings.is_empty());
+        assert!(output.summary().contains("achieved_quality="));
+
+        // Only reachable below the downscale quality floor at full size
+        let target = size_at(DOWNSCALE_QUALITY_FLOOR) / 3;
+        let options = CompressionOptions { target_size: Some(target), ..options };
+        let output = ImageType::compress_with_options(&jpeg, &options).unwrap();
+        assert!(output.data.len() <= target);
+        assert!(output.achieved_quality.unwrap() < DOWNSCALE_QUALITY_FLOOR);
+
+        let downscaled = CompressionOptions { allow_downscale: true, ..options.clone() };
+        let output = ImageType::compress_with_options(&jpeg, &downscaled).unwrap();
+        assert!(output.data.len() <= target);
+        assert!(output.achieved_quality.unwrap() >= DOWNSCALE_QUALITY_FLOOR);
+        let scale = output.scale.unwrap();
+        let (width, height) = image::load_from_memory(&output.data).unwrap().dimensions();
+        assert_eq!((width, height), ((256.0 * scale).round() as u32, (192.0 * scale).round() as u32));
+
+        // Unreachable: the smallest output comes back with a warning
+        let options = CompressionOptions { target_size: Some(100), ..options };
+        let output = ImageType::compress_with_options(&jpeg, &options).unwrap();
+        assert!(output.data.len() > 100);
+        assert_eq!(output.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_target_dssim() {
+        for format in [ImageFormat::Jpeg, ImageFormat::Png] {
+            let data = encode(&gradient(128, 96, true), format);
+            let options = |target| CompressionOptions {
+                target_dssim: Some(target),
+                respect_input_quality: false,
+                size_guard: None,
+                ..Default::default()
+            };
+
+            let strict = ImageType::compress_with_options(&data, &options(0.001)).unwrap();
+            let loose = ImageType::compress_with_options(&data, &options(0.02)).unwrap();
+            assert!(strict.dssim.unwrap() <= 0.001 && loose.dssim.unwrap() <= 0.02);
+            assert!(loose.achieved_quality.unwrap() <= strict.achieved_quality.unwrap());
+            assert!(loose.data.len() <= strict.data.len());
+            assert!(strict.summary().contains("dssim="));
+        }
+
+        // Transparency is scored on black and on white, the same as compare_images
+        let translucent = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 48, |x, y| {
+            image::Rgba([(x * 4) as u8, (y * 5) as u8, 90, ((x + y) * 3) as u8])
+        }));
+        let data = encode(&translucent, ImageFormat::Png);
+        let options = CompressionOptions { target_dssim: Some(0.005), size_guard: None, ..Default::default() };
+        let output = ImageType::compress_with_options(&data, &options).unwrap();
+        let measured = crate::metrics::compare_images(&data, &output.data).unwrap().dssim;
+        assert!((output.dssim.unwrap() - measured).abs() < 1e-12, "{:?} vs {}", output.dssim, measured);
+
+        let options: CompressionOptions = "target_ssim=0.99".parse().unwrap();
+        assert!((options.target_dssim.unwrap() - (1.0 / 0.99 - 1.0)).abs() < 1e-12);
+        let options = CompressionOptions { target_size: Some(1000), ..options };
+        assert!(ImageType::compress_with_options(&encode(&gradient(8, 8, true), ImageFormat::Png), &options).is_err());
+    }
+
+    #[test]
+    fn test_auto_format() {
+        let photo = encode(&gradient(96, 64, true), ImageFormat::Png);
+        let fixed = |format| {
+            let options = CompressionOptions { format: FormatChoice::Fixed(format), ..Default::default() };
+            ImageType::compress_with_options(&photo, &options).unwrap()
+        };
+        let webp = fixed(OutputFormat::WebP);
+        assert_eq!(webp.mime_type, Some("image/webp"));
+        assert_eq!(image::load_from_memory(&webp.data).unwrap().dimensions(), (96, 64));
+        assert_eq!(fixed(OutputFormat::Avif).mime_type, Some("image/avif"));
+        let lossless = fixed(OutputFormat::PngLossless);
+        assert_eq!(lossless.path, Some(CompressionPath::PngLossless));
+        assert_eq!(image::load_from_memory(&lossless.data).unwrap().to_rgb8(), gradient(96, 64, true).to_rgb8());
+
+        let options = CompressionOptions { format: FormatChoice::Auto, ..Default::default() };
+        let auto = ImageType::compress_with_options(&photo, &options).unwrap();
+        assert!(auto.dssim.unwrap() <= options.max_dssim);
+        assert!(auto.data.len() <= lossless.data.len());
+        let mime_type = image::guess_format(&auto.data).unwrap().to_mime_type();
+        assert_eq!(auto.mime_type, Some(mime_type));
+        assert!(auto.summary().contains(&format!("mime_type={}", mime_type)));
+
+        // Transparency rules JPEG out, even when it's the only other choice
+        let transparent = encode(
+            &DynamicImage::ImageRgba8(image::RgbaImage::from_fn(32, 32, |x, _| image::Rgba([200, 40, 40, (x * 8) as u8]))),
+            ImageFormat::Png,
+        );
+        let options = CompressionOptions {
+            format: FormatChoice::Auto,
+            allowed_formats: vec![OutputFormat::Jpeg, OutputFormat::WebP],
+            ..Default::default()
+        };
+        let output = ImageType::compress_with_options(&transparent, &options).unwrap();
+        assert_eq!(output.mime_type, Some("image/webp"));
+        assert!(image::load_from_memory(&output.data).unwrap().color().has_alpha());
+        let options = CompressionOptions { allowed_formats: vec![OutputFormat::Jpeg], ..options };
+        assert!(ImageType::compress_with_options(&transparent, &options).is_err());
+
+        if !ImageFormat::Avif.reading_enabled() {
+            let options = CompressionOptions {
+                format: FormatChoice::Auto,
+                allowed_formats: vec![OutputFormat::Avif, OutputFormat::Png],
+                ..Default::default()
+            };
+            let output = ImageType::compress_with_options(&photo, &options).unwrap();
+            assert_eq!(output.mime_type, Some("image/png"));
+            assert!(output.warnings.iter().any(|w| w.starts_with("avif skipped")));
+        }
+    }
+
+    #[test]
+    fn test_content_format() {
+        // A few flat colours: exact palette PNG
+        let logo = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            if (16..48).contains(&x) && (16..48).contains(&y) { Rgb([200, 30, 30]) } else { Rgb([255, 255, 255]) }
+        }));
+        let options = CompressionOptions { format: FormatChoice::Content, ..Default::default() };
+        let output = ImageType::compress_with_options(&encode(&logo, ImageFormat::Png), &options).unwrap();
+        assert_eq!(output.content, Some(ContentClass::Graphic));
+        assert_eq!(output.mime_type, Some("image/png"));
+        assert!(output.summary().contains("content=graphic"));
+
+        let luma_sampling = |jpeg: &[u8]| {
+            jpeg_segments(jpeg).iter().find(|s| crate::jpeg::is_sof_marker(s.marker)).map(|s| s.data[7])
+        };
+        let photo = encode(&gradient(64, 48, true), ImageFormat::Png);
+        let jpeg = |chroma_subsampling| {
+            let options = CompressionOptions {
+                format: FormatChoice::Fixed(OutputFormat::Jpeg),
+                chroma_subsampling,
+                ..Default::default()
+            };
+            ImageType::compress_with_options(&photo, &options).unwrap().data
+        };
+        assert_eq!(luma_sampling(&jpeg(ChromaSubsampling::Yuv420)), Some(0x22));
+        assert_eq!(luma_sampling(&jpeg(ChromaSubsampling::Yuv444)), Some(0x11));
+    }
+
+    #[test]
+    fn test_importance_map() {
+        // Fine detail everywhere; only the middle is the product
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(128, 96, |x, y| {
+            let v = ((x * 37 + y * 91) % 64) as u8 * 3;
+            Rgb([v, 255 - v, (x * 2) as u8])
+        }));
+        let data = encode(&img, ImageFormat::Png);
+        let compress = |importance| {
+            let options = CompressionOptions {
+                format: FormatChoice::Fixed(OutputFormat::Jpeg),
+                importance,
+                quality: 85,
+                ..Default::default()
+            };
+            let output = ImageType::compress_with_options(&data, &options).unwrap();
+            (output.data.len(), image::load_from_memory(&output.data).unwrap().to_rgb8())
+        };
+        let (plain_size, plain) = compress(None);
+        let (roi_size, roi) = compress(Some(ImportanceSource::Regions(vec![[0.25, 0.25, 0.5, 0.5]])));
+        assert!(roi_size < plain_size, "{} >= {}", roi_size, plain_size);
+        let mean_diff = |x0: u32, y0: u32| {
+            let diff: u32 = (y0..y0 + 16)
+                .flat_map(|y| (x0..x0 + 16).map(move |x| (x, y)))
+                .map(|(x, y)| (plain.get_pixel(x, y)[0] as i32 - roi.get_pixel(x, y)[0] as i32).unsigned_abs())
+                .sum();
+            diff as f64 / 256.0
+        };
+        assert!(mean_diff(56, 40) < 4.0);
+        assert!(mean_diff(0, 0) > 3.0 * mean_diff(56, 40).max(1.0));
+
+        let options: CompressionOptions = "importance=auto;max_colors=16".parse().unwrap();
+        let output = ImageType::compress_with_options(&data, &options).unwrap();
+        assert_eq!(output.path, Some(CompressionPath::PngPalette));
+    }
+
+    #[test]
+    fn test_adaptive_quality() {
+        let flat = DynamicImage::ImageRgb8(RgbImage::from_fn(96, 64, |x, _| {
+            if x < 48 { Rgb([30, 90, 160]) } else { Rgb([240, 240, 240]) }
+        }));
+        let texture = DynamicImage::ImageRgb8(RgbImage::from_fn(96, 64, |x, y| {
+            let n = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)).wrapping_mul(2_654_435_761);
+            Rgb([(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8])
+        }));
+        let options = CompressionOptions {
+            format: FormatChoice::Fixed(OutputFormat::Jpeg),
+            adaptive_quality: true,
+            size_guard: None,
+            ..Default::default()
+        };
+        let compress = |img: &DynamicImage, options: &CompressionOptions| {
+            ImageType::compress_with_options(&encode(img, ImageFormat::Png), options).unwrap()
+        };
+
+        let simple = compress(&flat, &options);
+        let busy = compress(&texture, &options);
+        assert!(simple.complexity.unwrap() < busy.complexity.unwrap());
+        assert!(simple.achieved_quality.unwrap() < options.quality);
+        assert!(busy.achieved_quality.unwrap() > options.quality);
+        assert!(busy.achieved_quality.unwrap() <= options.quality + options.adaptive_range);
+        assert!(simple.summary().contains("complexity="));
+
+        let fixed = CompressionOptions { adaptive_range: 0, ..options.clone() };
+        assert_eq!(compress(&flat, &fixed).achieved_quality, Some(options.quality));
+        let options: CompressionOptions = "adaptive_quality=true;adaptive_range=25".parse().unwrap();
+        assert!(options.adaptive_quality && options.adaptive_range == 25);
+        assert!("adaptive_range=60".parse::<CompressionOptions>().is_err());
+    }
+
+    #[test]
+    fn test_gain_map_jpeg() {
+        use crate::exif::tests::{orientation_tiff, with_exif};
+
+        let hdr = with_exif(&crate::gainmap::tests::ultra_hdr(64, 48), &orientation_tiff(6, false));
+        let options = CompressionOptions { respect_input_quality: false, size_guard: None, ..CompressionOptions::new(40) };
+        let output = ImageType::compress_with_options(&hdr, &options).unwrap();
+
+        // Both images were recompressed and rotated; the XMP points at the new gain map
+        let (primary, gain_map) = split_gain_map(&output.data).unwrap();
+        assert!(output.data.len() < hdr.len());
+        assert_eq!(image::load_from_memory(primary).unwrap().dimensions(), (48, 64));
+        assert_eq!(image::load_from_memory(gain_map).unwrap().dimensions(), (12, 16));
+        assert!(String::from_utf8_lossy(primary).contains(&format!("Item:Length=\"{}\"", gain_map.len())));
+        assert!(String::from_utf8_lossy(gain_map).contains("hdrgm:GainMapMax"));
+        assert!(crate::exif::jpeg_exif(gain_map).is_none());
+
+        // Base-image settings such as importance smoothing leave the gain map alone
+        let smoothed = CompressionOptions {
+            importance: Some(ImportanceSource::CenterWeighted),
+            background_blur: 8.0,
+            ..options
+        };
+        let smoothed = ImageType::compress_with_options(&hdr, &smoothed).unwrap();
+        assert_eq!(split_gain_map(&smoothed.data).unwrap().1, gain_map);
+    }
+
+    #[test]
+    fn test_metadata_policy() {
+        use crate::metadata::{read_metadata, write_metadata, ImageMetadata};
+
+        let source = ImageMetadata {
+            xmp: Some(b"<dc:rights>(c) Example Corp</dc:rights><dc:title>Holiday</dc:title>".to_vec()),
+            text: vec![("Comment".into(), "Shot on a phone".into())],
+            ..Default::default()
+        };
+        let jpeg = write_metadata(&encode(&gradient(48, 32, true), ImageFormat::Jpeg), &source).unwrap();
+        let png = write_metadata(&encode(&gradient(48, 32, true), ImageFormat::Png), &source).unwrap();
+
+        for input in [&jpeg, &png] {
+            for (jpeg_mode, respect_input_quality) in [(JpegMode::Reencode, false), (JpegMode::Lossless, true)] {
+                let options = CompressionOptions { jpeg_mode, respect_input_quality, size_guard: None, ..CompressionOptions::new(60) };
+
+                let stripped = ImageType::compress_with_options(input, &options).unwrap();
+                assert!(read_metadata(&stripped.data).is_empty());
+
+                let options = CompressionOptions { metadata: MetadataPolicy::Whitelist, ..options };
+                let whitelisted = read_metadata(&ImageType::compress_with_options(input, &options).unwrap().data);
+                let xmp = String::from_utf8(whitelisted.xmp.unwrap()).unwrap();
+                assert!(xmp.contains("(c) Example Corp") && !xmp.contains("Holiday"));
+                assert!(whitelisted.text.is_empty());
+
+                let options = CompressionOptions { metadata: MetadataPolicy::Keep, ..options };
+                let kept = read_metadata(&ImageType::compress_with_options(input, &options).unwrap().data);
+                assert_eq!(kept.text, source.text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_icc_profile_preserved() {
+        use crate::metadata::{png_chunks, read_metadata, write_metadata, ImageMetadata};
+        use moxcms::ColorProfile;
+
+        let p3 = ColorProfile::new_display_p3().encode().unwrap();
+        let srgb = ColorProfile::new_srgb().encode().unwrap();
+        let tagged = |format, icc: &Vec<u8>| {
+            let metadata = ImageMetadata { icc: Some(icc.clone()), ..Default::default() };
+            write_metadata(&encode(&gradient(48, 32, true), format), &metadata).unwrap()
+        };
+        let options = CompressionOptions { respect_input_quality: false, size_guard: None, ..CompressionOptions::new(60) };
+        let compress = |input: &[u8], options: &CompressionOptions| ImageType::compress_with_options(input, options).unwrap().data;
+
+        for format in [ImageFormat::Jpeg, ImageFormat::Png] {
+            let output = compress(&tagged(format, &p3), &options);
+            assert_eq!(read_metadata(&output).icc.as_ref(), Some(&p3));
+
+            let without = compress(&tagged(format, &p3), &CompressionOptions { keep_icc: false, ..options.clone() });
+            assert_eq!(read_metadata(&without).icc, None);
+        }
+
+        // sRGB shrinks to the sRGB chunk in PNG and disappears from JPEG
+        let png = tagged(ImageFormat::Png, &srgb);
+        let kinds: Vec<[u8; 4]> = png_chunks(&png).iter().map(|c| c.kind).collect();
+        assert!(kinds.contains(b"sRGB") && !kinds.contains(b"iCCP"));
+        assert_eq!(read_metadata(&tagged(ImageFormat::Jpeg, &srgb)).icc, None);
+
+        // An RGB profile doesn't describe grayscale output
+        let gray = write_metadata(
+            &encode(&gradient(48, 32, false), ImageFormat::Png),
+            &ImageMetadata { icc: Some(p3.clone()), ..Default::default() },
+        )
+        .unwrap();
+        assert_eq!(read_metadata(&compress(&gray, &options)).icc, None);
+    }
+
+    #[test]
+    fn test_convert_to_srgb_option() {
+        use crate::metadata::{read_metadata, write_metadata, ImageMetadata};
+
+        let adobe = moxcms::ColorProfile::new_adobe_rgb().encode().unwrap();
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(32, 32, Rgb([160, 40, 40])));
+        let input = write_metadata(
+            &encode(&img, ImageFormat::Png),
+            &ImageMetadata { icc: Some(adobe), ..Default::default() },
+        )
+        .unwrap();
+
+        let options = CompressionOptions { convert_to_srgb: true, size_guard: None, ..CompressionOptions::new(90) };
+        for output in [
+            do_png_compression_with_options(&input, &options).unwrap(),
+            do_jpeg_compression_with_options(&input, &options).unwrap(),
+        ] {
+            assert_eq!(read_metadata(&output.data).icc, None);
+            let pixel = image::load_from_memory(&output.data).unwrap().to_rgb8().get_pixel(16, 16).0;
+            // Same colour, expressed in sRGB: a deeper red than the raw numbers
+            assert!(pixel[0] > 170 && pixel[1] < 40, "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn test_size_guard_keeps_transformed_pixels() {
+        use crate::metadata::{read_metadata, write_metadata, ImageMetadata};
+
+        let adobe = moxcms::ColorProfile::new_adobe_rgb().encode().unwrap();
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(32, 32, Rgb([160, 40, 40])));
+        let input = write_metadata(
+            &encode(&img, ImageFormat::Png),
+            &ImageMetadata { icc: Some(adobe), ..Default::default() },
+        )
+        .unwrap();
+
+        // The guard would hand back the Adobe RGB original; the sRGB conversion wins
+        let options = CompressionOptions { convert_to_srgb: true, size_guard: Some(0.99), ..CompressionOptions::new(90) };
+        let output = ImageType::compress_with_options(&input, &options).unwrap();
+        assert!(!output.original_returned);
+        assert_eq!(read_metadata(&output.data).icc, None);
+        assert!(output.warnings.iter().any(|w| w.contains("converted to sRGB")), "{:?}", output.warnings);
+
+        // Same for a resize
+        let options = CompressionOptions { scale: 0.5, size_guard: Some(0.99), ..CompressionOptions::new(90) };
+        let output = ImageType::compress_with_options(&input, &options).unwrap();
+        assert!(!output.original_returned);
+        assert_eq!(image::load_from_memory(&output.data).unwrap().dimensions(), (16, 16));
+        assert!(output.warnings.iter().any(|w| w.contains("resized")), "{:?}", output.warnings);
+    }
+
+    #[test]
+    fn test_privacy_mode() {
+        use crate::exif::{image_orientation, write_exif, ExifField, ExifValue, TAG_GPS_IFD, TAG_ORIENTATION};
+        use crate::metadata::{read_metadata, write_metadata, ImageMetadata};
+
+        let exif = write_exif(
+            &[
+                ExifField { tag: TAG_ORIENTATION, field_type: 3, count: 1, value: ExifValue::Bytes(vec![0, 3]) },
+                ExifField {
+                    tag: TAG_GPS_IFD,
+                    field_type: 4,
+                    count: 1,
+                    value: ExifValue::Ifd(vec![ExifField {
+                        tag: 1,
+                        field_type: 2,
+                        count: 2,
+                        value: ExifValue::Bytes(b"N\0".to_vec()),
+                    }]),
+                },
+            ],
+            false,
+        );
+        let icc = moxcms::ColorProfile::new_display_p3().encode().unwrap();
+        let input = write_metadata(
+            &encode(&gradient(48, 32, true), ImageFormat::Jpeg),
+            &ImageMetadata { exif: Some(exif), icc: Some(icc.clone()), ..Default::default() },
+        )
+        .unwrap();
+
+        // A huge size guard would normally hand the original back
+        let options: CompressionOptions = "metadata=privacy;auto_orient=false;size_guard=0.99;jpeg_mode=lossless"
+            .parse()
+            .unwrap();
+        let output = ImageType::compress_with_options(&input, &options).unwrap();
+        assert!(!output.original_returned);
+        assert_eq!(output.removed_metadata, vec!["EXIF GPS"]);
+        assert!(output.summary().contains("removed_metadata.0=EXIF GPS"));
+
+        let kept = read_metadata(&output.data);
+        assert_eq!(image_orientation(&output.data), 3);
+        assert_eq!(kept.icc, Some(icc));
+        let (ifd0, _) = crate::exif::read_exif(kept.exif.as_deref().unwrap()).unwrap();
+        assert!(ifd0.iter().all(|f| f.tag != TAG_GPS_IFD));
+    }
+
+    #[test]
+    fn test_png_ancillary_chunks_preserved() {
+        use crate::metadata::{png_chunks, read_metadata, write_metadata, ImageMetadata, PixelDensity};
+
+        let source = ImageMetadata {
+            pixel_density: Some(PixelDensity { x: 3780, y: 3780, per_metre: true }),
+            gamma: Some(55555),
+            text: vec![("Title".into(), "Chart".into())],
+            ..Default::default()
+        };
+        let input = write_metadata(&encode(&gradient(48, 32, true), ImageFormat::Png), &source).unwrap();
+        let options = CompressionOptions { size_guard: None, ..CompressionOptions::new(80) };
+
+        // Gamma is a colour hint and stays by default, density and text follow the policy
+        let stripped = do_png_compression_with_options(&input, &options).unwrap();
+        assert_eq!(read_metadata(&stripped.data), ImageMetadata { gamma: Some(55555), ..Default::default() });
+
+        let kept = do_png_compression_with_options(&input, &CompressionOptions { metadata: MetadataPolicy::Keep, ..options }).unwrap();
+        assert_eq!(read_metadata(&kept.data), source);
+        let kinds: Vec<[u8; 4]> = png_chunks(&kept.data).iter().map(|c| c.kind).collect();
+        let position = |kind: &[u8; 4]| kinds.iter().position(|k| k == kind).unwrap();
+        assert!(position(b"gAMA") < position(b"PLTE") && position(b"pHYs") < position(b"IDAT"));
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options: CompressionOptions = "quality=60; background=#102030\nalpha=error;size_guard=off".parse().unwrap();
+        assert_eq!(options.quality, 60);
+        assert_eq!(options.background, [0x10, 0x20, 0x30]);
+        assert_eq!(options.alpha_policy, AlphaPolicy::Error);
+        assert_eq!(options.size_guard, None);
+
+        let options: CompressionOptions = "size_guard=0.1;respect_input_quality=false".parse().unwrap();
+        assert!(!options.respect_input_quality);
+        assert_eq!(options.size_guard, Some(0.1));
+        assert_eq!(options.quality, 75);
+
+        let options: CompressionOptions = "jpeg_mode=lossless;strip_metadata=false".parse().unwrap();
+        assert_eq!(options.jpeg_mode, JpegMode::Lossless);
+        assert_eq!(options.metadata, MetadataPolicy::Keep);
+        let options: CompressionOptions = "metadata=whitelist".parse().unwrap();
+        assert_eq!(options.metadata, MetadataPolicy::Whitelist);
+        let options: CompressionOptions = "speed=10;max_colors=16;dithering=0.5;posterize=2;min_quality=40"
+            .parse()
+            .unwrap();
+        assert_eq!((options.speed, options.max_colors, options.posterize, options.min_quality), (10, 16, 2, 40));
+        assert_eq!(options.dithering, 0.5);
+        assert!("speed=0".parse::<CompressionOptions>().is_err());
+        assert!("max_colors=300".parse::<CompressionOptions>().is_err());
+        assert!("dithering=1.5".parse::<CompressionOptions>().is_err());
+        let options: CompressionOptions = "sixteen_bit=dither".parse().unwrap();
+        assert_eq!(options.sixteen_bit, SixteenBitPolicy::Dither);
+        let options: CompressionOptions = "target_size=200KB;allow_downscale=true;scale=0.5".parse().unwrap();
+        assert_eq!((options.target_size, options.allow_downscale, options.scale), (Some(204800), true, 0.5));
+        assert_eq!(parse_byte_size("1.5m"), Ok(1572864));
+        assert_eq!(parse_byte_size("5000"), Ok(5000));
+        assert!("target_size=12XB".parse::<CompressionOptions>().is_err());
+        assert!("scale=0".parse::<CompressionOptions>().is_err());
+        let options: CompressionOptions = "format=auto;allowed_formats=png, webp;max_dssim=0.005".parse().unwrap();
+        assert_eq!(options.format, FormatChoice::Auto);
+        assert_eq!(options.allowed_formats, vec![OutputFormat::Png, OutputFormat::WebP]);
+        assert_eq!(options.max_dssim, 0.005);
+        let options: CompressionOptions = "format=jpg".parse().unwrap();
+        assert_eq!(options.format, FormatChoice::Fixed(OutputFormat::Jpeg));
+        assert!("format=gif".parse::<CompressionOptions>().is_err());
+        assert!("allowed_formats=".parse::<CompressionOptions>().is_err());
+        let options: CompressionOptions = "format=content;chroma_subsampling=4:4:4".parse().unwrap();
+        assert_eq!((options.format, options.chroma_subsampling), (FormatChoice::Content, ChromaSubsampling::Yuv444));
+        let options: CompressionOptions = "importance=regions:0.1,0.2,0.5,0.5|0,0,1,0.1".parse().unwrap();
+        assert_eq!(
+            options.importance,
+            Some(ImportanceSource::Regions(vec![[0.1, 0.2, 0.5, 0.5], [0.0, 0.0, 1.0, 0.1]]))
+        );
+        assert!("importance=regions:0.1,0.2,0.5".parse::<CompressionOptions>().is_err());
+        assert!("importance=faces".parse::<CompressionOptions>().is_err());
+
+        assert!("quality=101".parse::<CompressionOptions>().is_err());
+        assert!("background=#12".parse::<CompressionOptions>().is_err());
+        assert!("unknown=1".parse::<CompressionOptions>().is_err());
+        assert!("quality".parse::<CompressionOptions>().is_err());
+    }
+
+    #[test]
+    fn test_quantization_tuning() {
+        let data = encode(&gradient(64, 64, true), ImageFormat::Png);
+        let options = CompressionOptions { max_colors: 8, speed: 10, dithering: 0.0, ..CompressionOptions::new(90) };
+        let output = do_png_compression_with_options(&data, &options).unwrap();
+        let decoded = image::load_from_memory(&output.data).unwrap().to_rgb8();
+        let mut colors: Vec<[u8; 3]> = decoded.pixels().map(|p| p.0).collect();
+        colors.sort();
+        colors.dedup();
+        assert!(colors.len() <= 8);
+
+        // Eight colours can't reach quality 95 on a colour gradient
+        let strict = CompressionOptions { min_quality: 95, ..options };
+        let err = do_png_compression_with_options(&data, &strict).unwrap_err();
+        assert!(err.contains("min_quality"), "{}", err);
+    }
+
+    fn gradient16() -> Vec<u8> {
+        let img = image::ImageBuffer::from_fn(64, 8, |x, y| {
+            image::Rgba([(x * 1000 + y) as u16, 40000, (65535 - x * 900) as u16, 65535u16])
+        });
+        encode(&DynamicImage::ImageRgba16(img), ImageFormat::Png)
+    }
+
+    #[test]
+    fn test_sixteen_bit_lossless() {
+        let data = gradient16();
+        let options = CompressionOptions { sixteen_bit: SixteenBitPolicy::Lossless, ..CompressionOptions::new(80) };
+        let output = do_png_compression_with_options(&data, &options).unwrap();
+
+        let decoder = png::Decoder::new(Cursor::new(&output.data));
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().bit_depth, png::BitDepth::Sixteen);
+        assert_eq!(reader.info().color_type, png::ColorType::Rgb);
+
+        let before = image::load_from_memory(&data).unwrap().to_rgba16();
+        let after = image::load_from_memory(&output.data).unwrap().to_rgba16();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn test_sixteen_bit_dither() {
+        let data = gradient16();
+        let options = CompressionOptions { sixteen_bit: SixteenBitPolicy::Dither, ..CompressionOptions::new(80) };
+        let output = do_png_compression_with_options(&data, &options).unwrap();
+        let decoded = image::load_from_memory(&output.data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (64, 8));
+
+        // A flat 16-bit level between two 8-bit steps dithers into a mix of both
+        let flat = image::ImageBuffer::from_pixel(16, 16, image::Rgba([32896u16 + 128, 0, 0, 65535]));
+        let dithered = dither_to_rgba8(&flat);
+        let mut levels: Vec<u8> = dithered.pixels().map(|p| p[0]).collect();
+        levels.sort();
+        levels.dedup();
+        assert_eq!(levels, vec![128, 129]);
+    }
+
+    #[test]
+    fn test_grayscale_jpeg_output() {
+        let data = encode(&gradient(64, 48, false), ImageFormat::Jpeg);
+        let compressed = do_jpeg_compression(&data, 80).unwrap();
+        let decoded = image::load_from_memory(&compressed).unwrap();
+        assert!(matches!(decoded.color(), image::ColorType::L8));
+
+        // Chroma rounding noise from a YCbCr JPEG still counts as gray, but the same
+        // spread in a lossless source is colour
+        let noisy = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+            let v = (40 + (x * 2 + y) % 160) as u8;
+            Rgb([v + (x % 2) as u8, v, v + (y % 2) as u8])
+        }));
+        let options = CompressionOptions { size_guard: None, respect_input_quality: false, ..CompressionOptions::new(80) };
+        let compressed = do_jpeg_compression_with_options(&encode(&noisy, ImageFormat::Jpeg), &options).unwrap();
+        let decoded = image::load_from_memory(&compressed.data).unwrap();
+        assert!(matches!(decoded.color(), image::ColorType::L8));
+        let compressed = do_jpeg_compression_with_options(&encode(&noisy, ImageFormat::Png), &options).unwrap();
+        let decoded = image::load_from_memory(&compressed.data).unwrap();
+        assert!(matches!(decoded.color(), image::ColorType::Rgb8));
+        assert!(is_grayscale_rgb(&[9, 10, 11], JPEG_GRAYSCALE_TOLERANCE));
+        assert!(!is_grayscale_rgb(&[9, 10, 12], JPEG_GRAYSCALE_TOLERANCE));
+        assert!(!is_opaque_grayscale_rgba(&[10, 10, 11, 255]));
+
+        let colored = encode(&gradient(64, 48, true), ImageFormat::Jpeg);
+        let decoded = image::load_from_memory(&do_jpeg_compression(&colored, 80).unwrap()).unwrap();
+        assert!(matches!(decoded.color(), image::ColorType::Rgb8));
+    }
+}
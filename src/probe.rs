@@ -0,0 +1,257 @@
+use image::ImageFormat;
+use crate::exif::{exif_orientation, jpeg_exif, png_exif};
+use crate::jpeg::{estimate_jpeg_quality, is_sof_marker, jpeg_segments};
+use crate::metadata::{is_webp, png_chunks, webp_canvas, webp_chunks};
+
+/// What the header of an image says about it, read without decoding any pixels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// Container format (JPEG, PNG, WebP or GIF)
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per sample (8 for WebP and GIF)
+    pub bit_depth: u8,
+    /// Alpha channel or transparency (PNG tRNS, GIF transparent index)
+    pub has_alpha: bool,
+    /// 1 for still images; APNG, animated WebP and GIF report their frame count
+    pub frame_count: u32,
+    /// EXIF orientation (1-8), 1 when the file doesn't say
+    pub orientation: u8,
+    /// Whether an embedded ICC profile is present
+    pub has_icc: bool,
+    /// Quality the JPEG was saved with, estimated from its DQT tables
+    pub jpeg_quality: Option<u8>,
+}
+
+impl ImageInfo {
+    /// Lowercase name of the format, e.g. `jpeg`
+    pub fn format_name(&self) -> String {
+        format!("{:?}", self.format).to_lowercase()
+    }
+
+    /// Probe result as `key=value` lines (`java.util.Properties` format)
+    pub fn summary(&self) -> String {
+        let mut lines = vec![
+            format!("format={}", self.format_name()),
+            format!("width={}", self.width),
+            format!("height={}", self.height),
+            format!("bit_depth={}", self.bit_depth),
+            format!("has_alpha={}", self.has_alpha),
+            format!("frame_count={}", self.frame_count),
+            format!("orientation={}", self.orientation),
+            format!("has_icc={}", self.has_icc),
+        ];
+        if let Some(quality) = self.jpeg_quality {
+            lines.push(format!("jpeg_quality={}", quality));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Read format, dimensions and the other `ImageInfo` fields from the image
+/// headers alone. Much cheaper than `image::load_from_memory`.
+pub fn probe_image(data: &[u8]) -> Result<ImageInfo, String> {
+    if data.starts_with(b"\xff\xd8") {
+        probe_jpeg(data)
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        probe_png(data)
+    } else if is_webp(data) {
+        probe_webp(data)
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        probe_gif(data)
+    } else {
+        Err("Unsupported image format".to_string())
+    }
+}
+
+fn probe_jpeg(data: &[u8]) -> Result<ImageInfo, String> {
+    let segments = jpeg_segments(data);
+    let sof = segments
+        .iter()
+        .find(|s| is_sof_marker(s.marker) && s.data.len() >= 6)
+        .ok_or("JPEG has no frame header")?;
+
+    Ok(ImageInfo {
+        format: ImageFormat::Jpeg,
+        width: u16::from_be_bytes([sof.data[3], sof.data[4]]) as u32,
+        height: u16::from_be_bytes([sof.data[1], sof.data[2]]) as u32,
+        bit_depth: sof.data[0],
+        has_alpha: false,
+        frame_count: 1,
+        orientation: jpeg_exif(data).and_then(exif_orientation).unwrap_or(1),
+        has_icc: segments.iter().any(|s| s.marker == 0xE2 && s.data.starts_with(b"ICC_PROFILE\0")),
+        jpeg_quality: estimate_jpeg_quality(data),
+    })
+}
+
+fn probe_png(data: &[u8]) -> Result<ImageInfo, String> {
+    let chunks = png_chunks(data);
+    let ihdr = chunks
+        .first()
+        .filter(|c| &c.kind == b"IHDR" && c.data.len() >= 13)
+        .ok_or("PNG has no IHDR chunk")?;
+    let color_type = ihdr.data[9];
+    let frame_count = chunks
+        .iter()
+        .find(|c| &c.kind == b"acTL" && c.data.len() >= 8)
+        .map_or(1, |c| u32::from_be_bytes([c.data[0], c.data[1], c.data[2], c.data[3]]));
+
+    Ok(ImageInfo {
+        format: ImageFormat::Png,
+        width: u32::from_be_bytes([ihdr.data[0], ihdr.data[1], ihdr.data[2], ihdr.data[3]]),
+        height: u32::from_be_bytes([ihdr.data[4], ihdr.data[5], ihdr.data[6], ihdr.data[7]]),
+        bit_depth: ihdr.data[8],
+        has_alpha: color_type & 4 != 0 || chunks.iter().any(|c| &c.kind == b"tRNS"),
+        frame_count,
+        orientation: png_exif(data).and_then(exif_orientation).unwrap_or(1),
+        has_icc: chunks.iter().any(|c| &c.kind == b"iCCP"),
+        jpeg_quality: None,
+    })
+}
+
+fn probe_webp(data: &[u8]) -> Result<ImageInfo, String> {
+    let chunks = webp_chunks(data);
+    let (width, height, has_alpha, animated) = match chunks.first() {
+        Some((kind, body)) if kind == b"VP8X" && body.len() >= 10 => {
+            let width = u32::from_le_bytes([body[4], body[5], body[6], 0]) + 1;
+            let height = u32::from_le_bytes([body[7], body[8], body[9], 0]) + 1;
+            (width, height, body[0] & 0x10 != 0, body[0] & 0x02 != 0)
+        }
+        Some((kind, body)) => {
+            let (width, height, alpha) = webp_canvas(kind, body).ok_or("WebP has no image header")?;
+            (width, height, alpha, false)
+        }
+        None => return Err("WebP has no chunks".to_string()),
+    };
+    let exif = chunks
+        .iter()
+        .find(|(kind, _)| kind == b"EXIF")
+        .map(|(_, body)| body.strip_prefix(b"Exif\0\0").unwrap_or(body));
+
+    Ok(ImageInfo {
+        format: ImageFormat::WebP,
+        width,
+        height,
+        bit_depth: 8,
+        has_alpha,
+        frame_count: if animated {
+            chunks.iter().filter(|(kind, _)| kind == b"ANMF").count() as u32
+        } else {
+            1
+        },
+        orientation: exif.and_then(exif_orientation).unwrap_or(1),
+        has_icc: chunks.iter().any(|(kind, _)| kind == b"ICCP"),
+        jpeg_quality: None,
+    })
+}
+
+/// Walk the GIF block structure, skipping over (not decoding) the image data
+fn probe_gif(data: &[u8]) -> Result<ImageInfo, String> {
+    let header = data.get(..13).ok_or("GIF header is truncated")?;
+    let color_table_size = |packed: u8| if packed & 0x80 != 0 { 3usize << ((packed & 7) + 1) } else { 0 };
+    // Sub-blocks are length-prefixed and end with a zero-length block
+    let skip_sub_blocks = |mut pos: usize| -> Option<usize> {
+        loop {
+            let length = *data.get(pos)? as usize;
+            pos += 1 + length;
+            if length == 0 {
+                return Some(pos);
+            }
+        }
+    };
+
+    let mut frame_count = 0;
+    let mut has_alpha = false;
+    let mut pos = 13 + color_table_size(header[10]);
+    while let Some(&block) = data.get(pos) {
+        let next = match block {
+            // Extension; a graphic control extension carries the transparency flag
+            0x21 => {
+                if data.get(pos + 1) == Some(&0xF9) && data.get(pos + 3).is_some_and(|flags| flags & 1 != 0) {
+                    has_alpha = true;
+                }
+                skip_sub_blocks(pos + 2)
+            }
+            // Image descriptor, optional local colour table, LZW code size, then image data
+            0x2C => {
+                frame_count += 1;
+                data.get(pos + 9)
+                    .and_then(|&packed| skip_sub_blocks(pos + 10 + color_table_size(packed) + 1))
+            }
+            _ => None,
+        };
+        match next {
+            Some(next) => pos = next,
+            None => break,
+        }
+    }
+
+    Ok(ImageInfo {
+        format: ImageFormat::Gif,
+        width: u16::from_le_bytes([header[6], header[7]]) as u32,
+        height: u16::from_le_bytes([header[8], header[9]]) as u32,
+        bit_depth: 8,
+        has_alpha,
+        frame_count: frame_count.max(1),
+        orientation: 1,
+        has_icc: false,
+        jpeg_quality: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exif::tests::{orientation_tiff, with_exif};
+    use crate::test_support::{encode, sample};
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    #[test]
+    fn test_probe_jpeg() {
+        let jpeg = with_exif(&sample(ImageFormat::Jpeg), &orientation_tiff(6, true));
+        let info = probe_image(&jpeg).unwrap();
+        let img = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!(info.format, ImageFormat::Jpeg);
+        assert_eq!((info.width, info.height), (img.width(), img.height()));
+        assert_eq!(info.bit_depth, 8);
+        assert_eq!(info.orientation, 6);
+        assert!(!info.has_alpha && !info.has_icc);
+        assert!(info.jpeg_quality.is_some());
+        assert!(info.summary().contains("format=jpeg\nwidth="));
+    }
+
+    #[test]
+    fn test_probe_png_and_webp() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(37, 21, Rgba([10, 20, 30, 128])));
+
+        let info = probe_image(&encode(&img, ImageFormat::Png)).unwrap();
+        assert_eq!((info.format, info.width, info.height), (ImageFormat::Png, 37, 21));
+        assert!(info.has_alpha);
+        assert_eq!((info.bit_depth, info.frame_count, info.jpeg_quality), (8, 1, None));
+
+        let rgb16 = DynamicImage::ImageRgb16(img.to_rgb16());
+        let info = probe_image(&encode(&rgb16, ImageFormat::Png)).unwrap();
+        assert_eq!(info.bit_depth, 16);
+        assert!(!info.has_alpha);
+
+        let info = probe_image(&encode(&img, ImageFormat::WebP)).unwrap();
+        assert_eq!((info.format, info.width, info.height), (ImageFormat::WebP, 37, 21));
+        assert!(info.has_alpha);
+    }
+
+    #[test]
+    fn test_probe_gif() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(9, 4, Rgba([0, 0, 0, 0])));
+        let info = probe_image(&encode(&img, ImageFormat::Gif)).unwrap();
+        assert_eq!((info.format, info.width, info.height), (ImageFormat::Gif, 9, 4));
+        assert_eq!(info.frame_count, 1);
+        assert!(info.has_alpha);
+    }
+
+    #[test]
+    fn test_probe_rejects_unknown_data() {
+        assert!(probe_image(b"not an image").is_err());
+        assert!(probe_image(b"\xff\xd8\xff\xd9").is_err());
+    }
+}
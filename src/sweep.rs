@@ -0,0 +1,113 @@
+use crate::metadata::read_metadata;
+use crate::metrics::{compare_decoded, QualityMetrics};
+use crate::opts::{decode_image, encode_pixels, grayscale_tolerance, CompressionOptions, OutputFormat};
+
+/// One point of a rate-distortion curve
+#[derive(Debug, Clone, PartialEq)]
+pub struct RatePoint {
+    pub format: OutputFormat,
+    pub quality: u8,
+    /// Encoded size without metadata
+    pub bytes: usize,
+    /// The encoded image measured against the source
+    pub metrics: QualityMetrics,
+}
+
+impl RatePoint {
+    /// Bits per pixel of the encoded image
+    pub fn bits_per_pixel(&self, width: u32, height: u32) -> f64 {
+        self.bytes as f64 * 8.0 / (width as f64 * height as f64).max(1.0)
+    }
+}
+
+/// Encode an image at every quality in `qualities`, in every format of `formats`,
+/// and measure each result against the source. The source is decoded once (with
+/// `options`' orientation, colour conversion and scale) and its pixels are shared
+/// by every point; only the outputs are decoded to measure them. Points come back
+/// grouped by format, in the order given. Sizes exclude metadata, which doesn't
+/// depend on quality.
+pub fn quality_sweep(
+    data: &[u8],
+    formats: &[OutputFormat],
+    qualities: &[u8],
+    options: &CompressionOptions,
+) -> Result<Vec<RatePoint>, String> {
+    if let Some(format) = formats.iter().find(|format| !format.container().reading_enabled()) {
+        return Err(format!("{} output cannot be decoded in this build to measure it", format.name()));
+    }
+    if let Some(quality) = qualities.iter().find(|&&quality| quality > 100) {
+        return Err(format!("Quality must be between 0 and 100, got: {}", quality));
+    }
+
+    let source = decode_image(data, options)?;
+    let gamma = read_metadata(data).quantization_gamma();
+    let gray_tolerance = grayscale_tolerance(data);
+    // The source pixels are already upright, converted and resized
+    let output_options =
+        CompressionOptions { auto_orient: false, convert_to_srgb: false, scale: 1.0, ..options.clone() };
+
+    let mut points = Vec::with_capacity(formats.len() * qualities.len());
+    for &format in formats {
+        for &quality in qualities {
+            let quality_options = CompressionOptions { quality, ..options.clone() };
+            let encoded = encode_pixels(&source, gamma, gray_tolerance, &quality_options, format)?;
+            let decoded = decode_image(&encoded.data, &output_options)?;
+            points.push(RatePoint {
+                format,
+                quality,
+                bytes: encoded.data.len(),
+                metrics: compare_decoded(&source, &decoded)?,
+            });
+        }
+    }
+    Ok(points)
+}
+
+/// Sweep points as `key=value` lines (`java.util.Properties` format), one
+/// `point.N.<field>` group per point
+pub fn sweep_summary(points: &[RatePoint]) -> String {
+    let mut lines = vec![format!("points={}", points.len())];
+    for (i, point) in points.iter().enumerate() {
+        lines.push(format!("point.{}.format={}", i, point.format.name()));
+        lines.push(format!("point.{}.quality={}", i, point.quality));
+        lines.push(format!("point.{}.bytes={}", i, point.bytes));
+        for line in point.metrics.summary().lines() {
+            lines.push(format!("point.{}.{}", i, line));
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::encode;
+    use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+
+    #[test]
+    fn test_quality_sweep() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(96, 64, |x, y| {
+            Rgb([(x * 2 + y) as u8, (y * 3) as u8, ((x * y) % 256) as u8])
+        }));
+        let data = encode(&img, ImageFormat::Png);
+
+        let qualities = [30, 50, 70, 90];
+        let formats = [OutputFormat::Jpeg, OutputFormat::WebP, OutputFormat::Png];
+        let points = quality_sweep(&data, &formats, &qualities, &CompressionOptions::default()).unwrap();
+        assert_eq!(points.len(), 12);
+        assert_eq!((points[0].format, points[0].quality), (OutputFormat::Jpeg, 30));
+        assert_eq!((points[11].format, points[11].quality), (OutputFormat::Png, 90));
+
+        // Rate and quality both rise with the quality setting
+        let jpeg = &points[..4];
+        assert!(jpeg.windows(2).all(|pair| pair[0].bytes < pair[1].bytes));
+        assert!(jpeg[3].metrics.dssim < jpeg[0].metrics.dssim);
+        assert!(jpeg[3].bits_per_pixel(96, 64) > jpeg[0].bits_per_pixel(96, 64));
+
+        let summary = sweep_summary(&points);
+        assert!(summary.starts_with("points=12\npoint.0.format=jpeg\npoint.0.quality=30"));
+        assert!(summary.contains("point.11.dssim="));
+
+        assert!(quality_sweep(&data, &formats, &[101], &CompressionOptions::default()).is_err());
+    }
+}
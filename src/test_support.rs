@@ -0,0 +1,26 @@
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+
+/// `img` encoded as `format` by the `image` crate
+pub(crate) fn encode(img: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+    let mut data = Vec::new();
+    img.write_to(&mut Cursor::new(&mut data), format).unwrap();
+    data
+}
+
+/// A diagonal ramp, either gray or with the channels running apart
+pub(crate) fn gradient(width: u32, height: u32, colored: bool) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        let v = ((x * 7 + y * 3) % 256) as u8;
+        if colored { Rgb([v, 255 - v, v / 2]) } else { Rgb([v, v, v]) }
+    }))
+}
+
+/// A small colour image encoded as `format`
+pub(crate) fn sample(format: ImageFormat) -> Vec<u8> {
+    let img = DynamicImage::ImageRgb8(RgbImage::from_fn(48, 32, |x, y| {
+        Rgb([(x * 5) as u8, (y * 7) as u8, ((x + y) * 3) as u8])
+    }));
+    encode(&img, format)
+}
@@ -181,6 +181,54 @@ fn test_compression_trait_with_sample_files() {
     println!("Compression trait test completed successfully!\n");
 }
 
+#[test]
+fn test_compression_quality_metrics() {
+    // 生成测试图像，不依赖示例文件
+    let img = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(160, 120, |x, y| {
+        image::Rgb([(x + y) as u8, (y * 2) as u8, 128])
+    }));
+    let mut jpeg_data = Vec::new();
+    img.write_to(&mut std::io::Cursor::new(&mut jpeg_data), image::ImageFormat::Jpeg)
+        .expect("Failed to encode test JPEG");
+
+    let low = do_jpeg_compression(&jpeg_data, 20).expect("JPEG compression failed");
+    let high = do_jpeg_compression(&jpeg_data, 90).expect("JPEG compression failed");
+    let low_metrics = compare_images(&jpeg_data, &low).expect("Failed to compare images");
+    let high_metrics = compare_images(&jpeg_data, &high).expect("Failed to compare images");
+
+    println!("JPEG q20: {}", low_metrics.summary().replace('\n', ", "));
+    println!("JPEG q90: {}", high_metrics.summary().replace('\n', ", "));
+
+    assert!(high_metrics.psnr > low_metrics.psnr);
+    assert!(high_metrics.dssim < low_metrics.dssim);
+    assert!(high_metrics.ssim > 0.95 && high_metrics.ms_ssim > 0.95);
+}
+
+#[test]
+fn test_truncated_jpeg_returns_error() {
+    // libjpeg 的致命错误必须变成 Err，而不是让进程（以及 JVM）中止；
+    // 用 `cargo test --release` 运行可覆盖发布配置
+    let img = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(160, 120, |x, y| {
+        image::Rgb([(x * 3) as u8, (y * 2) as u8, ((x * y) % 256) as u8])
+    }));
+    let mut jpeg_data = Vec::new();
+    img.write_to(&mut std::io::Cursor::new(&mut jpeg_data), image::ImageFormat::Jpeg)
+        .expect("Failed to encode test JPEG");
+
+    // 截断在头部之内：没有可解码的图像
+    let truncated = &jpeg_data[..100];
+    let default_options = CompressionOptions::default();
+    let lossless_options = CompressionOptions { jpeg_mode: JpegMode::Lossless, ..Default::default() };
+    assert!(ImageType::compress_with_options(truncated, &default_options).is_err());
+    assert!(ImageType::compress_with_options(truncated, &lossless_options).is_err());
+    assert!(do_jpeg_lossless_optimization(truncated, true).is_err());
+
+    // 截断在扫描数据中：libjpeg 只发出警告，结果可以是 Ok 或 Err，但不能中止
+    let truncated = &jpeg_data[..jpeg_data.len() / 2];
+    let _ = ImageType::compress_with_options(truncated, &default_options);
+    let _ = ImageType::compress_with_options(truncated, &lossless_options);
+}
+
 #[test]
 fn test_image_type_detection() {
     println!("Testing image type detection...\n");